@@ -0,0 +1,68 @@
+// Word statistics of notes for `GET /notes/:id/analytics`, and similarity of
+// notes for finding duplicates.
+
+use crate::conv::plain_text;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+
+// The most frequent English function words.
+const ENGLISH_STOP_WORDS: &[&str] = &[
+    "a", "about", "after", "all", "also", "am", "an", "and", "any", "are", "as", "at", "be",
+    "because", "been", "but", "by", "can", "could", "did", "do", "does", "for", "from", "had",
+    "has", "have", "he", "her", "him", "his", "how", "i", "if", "in", "into", "is", "it", "its",
+    "just", "me", "my", "no", "not", "of", "on", "or", "our", "out", "she", "so", "some", "than",
+    "that", "the", "their", "them", "then", "there", "these", "they", "this", "to", "up", "us",
+    "was", "we", "were", "what", "when", "which", "who", "will", "with", "would", "you", "your",
+];
+
+/// Words left out of the counts.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopWords {
+    /// Common English words such as "the" and "and"
+    #[default]
+    English,
+    None,
+}
+
+impl StopWords {
+    fn contains(self, word: &str) -> bool {
+        match self {
+            StopWords::English => ENGLISH_STOP_WORDS.binary_search(&word).is_ok(),
+            StopWords::None => false,
+        }
+    }
+}
+
+/// How often every word of `markdown` occurs, ignoring case, formatting and
+/// code. Words are runs of letters and digits, with apostrophes inside them
+/// (`don't`) kept.
+pub fn word_frequency(markdown: &str, stop_words: StopWords) -> BTreeMap<String, usize> {
+    let mut frequency = BTreeMap::new();
+    for word in plain_text(markdown).split(|c: char| !c.is_alphanumeric() && c != '\'' && c != '’')
+    {
+        let word = word.trim_matches(['\'', '’']).to_lowercase();
+        if !word.is_empty() && !stop_words.contains(&word) {
+            *frequency.entry(word).or_insert(0) += 1;
+        }
+    }
+    frequency
+}
+
+/// The distinct words of `text` in lowercase, Markdown syntax included.
+pub fn word_set(text: &str) -> BTreeSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Share of the words in either set that are in both, from 0 (none) to 1
+/// (the same words). Two texts without words count as different.
+pub fn jaccard_similarity(a: &BTreeSet<String>, b: &BTreeSet<String>) -> f64 {
+    let union = a.union(b).count();
+    if union == 0 {
+        return 0.0;
+    }
+    a.intersection(b).count() as f64 / union as f64
+}
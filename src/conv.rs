@@ -0,0 +1,550 @@
+// Markdown to HTML conversion of notes.
+
+use comrak::{
+    adapters::{HeadingAdapter, HeadingMeta},
+    html::Anchorizer,
+    markdown_to_html_with_plugins,
+    nodes::{NodeValue, Sourcepos},
+    parse_document,
+    plugins::syntect::{SyntectAdapter, SyntectAdapterBuilder},
+    Arena, Options, Plugins,
+};
+use regex::{Captures, Regex};
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, LazyLock, Mutex},
+};
+use syntect::highlighting::ThemeSet;
+
+// `> [!NOTE]` on the first line of a blockquote, optionally followed by a
+// title, as in GitHub and Obsidian.
+static CALLOUT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"<blockquote>\n<p>\[!(?i:(note|tip|important|warning|caution))\][ \t]*(.*?)(</p>\n|\n)",
+    )
+    .unwrap()
+});
+
+// Loading the syntax definitions takes a while, so there is one highlighter
+// per theme for the life of the process.
+static HIGHLIGHTERS: LazyLock<Mutex<HashMap<String, Arc<SyntectAdapter>>>> =
+    LazyLock::new(Default::default);
+
+/// How heading ids (used by anchors and `#heading` links) are generated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum HeadingAnchors {
+    /// GitHub-style slugs of the heading text, e.g. `getting-started`
+    #[default]
+    Github,
+    /// Position of the heading in the note, e.g. `1`, `2`
+    Numeric,
+    /// No heading ids
+    None,
+}
+
+/// Settings that affect how a note's Markdown is turned into HTML.
+#[derive(Clone, Default)]
+pub struct RenderConfig {
+    pub max_line_length: Option<usize>,
+    pub heading_anchors: HeadingAnchors,
+    pub heading_id_prefix: String,
+    /// syntect theme code blocks are highlighted with, or none for plain code
+    pub syntax_theme: Option<String>,
+}
+
+/// Names of the themes `syntax_theme` can be, e.g. `InspiredGitHub`.
+pub fn syntax_themes() -> Vec<String> {
+    ThemeSet::load_defaults().themes.into_keys().collect()
+}
+
+pub fn md_to_html(markdown: &str, config: &RenderConfig) -> String {
+    let options = options();
+    let anchors = HeadingIds::new(config);
+    let highlighter = config.syntax_theme.as_deref().map(highlighter);
+    let mut plugins = Plugins::default();
+    if config.heading_anchors != HeadingAnchors::None {
+        plugins.render.heading_adapter = Some(&anchors);
+    }
+    if let Some(highlighter) = &highlighter {
+        plugins.render.codefence_syntax_highlighter = Some(highlighter.as_ref());
+    }
+    let html = markdown_to_html_with_plugins(markdown, &options, &plugins);
+    let html = match config.max_line_length {
+        Some(max) => collapse_long_lines(&html, max),
+        None => html,
+    };
+    render_callouts(&html)
+}
+
+fn options() -> Options<'static> {
+    let mut options = Options::default();
+    options.extension.strikethrough = true;
+    options.extension.tagfilter = true;
+    options.extension.table = true;
+    options.extension.autolink = true;
+    options.extension.tasklist = true;
+    options.extension.superscript = true;
+    options.extension.footnotes = true;
+    options.extension.description_lists = true;
+    // keeps `_` and `*` in formulas from being read as emphasis
+    options.extension.math_dollars = true;
+    options.extension.math_code = true;
+    options.render.unsafe_ = true;
+    options
+}
+
+/// URLs of the links in `markdown`, autolinks included, in order of first
+/// appearance.
+pub fn extract_links(markdown: &str) -> Vec<String> {
+    let arena = Arena::new();
+    let root = parse_document(&arena, markdown, &options());
+
+    let mut links = Vec::new();
+    for node in root.descendants() {
+        if let NodeValue::Link(link) = &node.data.borrow().value {
+            if !links.contains(&link.url) {
+                links.push(link.url.clone());
+            }
+        }
+    }
+    links
+}
+
+/// The prose of `markdown`: its text without formatting, code or HTML, with
+/// a space between paragraphs, list items etc.
+pub fn plain_text(markdown: &str) -> String {
+    let arena = Arena::new();
+    let root = parse_document(&arena, markdown, &options());
+
+    let mut text = String::new();
+    for node in root.descendants() {
+        match &node.data.borrow().value {
+            NodeValue::Text(literal) => text.push_str(literal),
+            NodeValue::SoftBreak | NodeValue::LineBreak => text.push(' '),
+            value if value.block() => text.push(' '),
+            _ => {}
+        }
+    }
+    text
+}
+
+// Highlights code with inline styles, so the HTML needs no stylesheet.
+fn highlighter(theme: &str) -> Arc<SyntectAdapter> {
+    let mut highlighters = HIGHLIGHTERS.lock().unwrap();
+    highlighters
+        .entry(theme.to_string())
+        .or_insert_with(|| Arc::new(SyntectAdapterBuilder::new().theme(theme).build()))
+        .clone()
+}
+
+// Turns blockquotes starting with a `[!TYPE]` marker into callouts. The rest
+// of the first paragraph, if any, stays in the callout as its own paragraph.
+fn render_callouts(html: &str) -> String {
+    CALLOUT
+        .replace_all(html, |caps: &Captures| {
+            let kind = caps[1].to_lowercase();
+            let title = match &caps[2] {
+                "" => kind[..1].to_uppercase() + &kind[1..],
+                title => title.to_string(),
+            };
+            let rest = if &caps[3] == "\n" { "<p>" } else { "" };
+            format!(
+                "<blockquote class=\"callout\" data-callout=\"{kind}\">\n<p class=\"calloutTitle\">{title}</p>\n{rest}"
+            )
+        })
+        .into_owned()
+}
+
+// Pathological lines (e.g. a pasted base64 blob) break the page layout, so
+// text running on for more than `max` characters is cut, with the rest in a
+// collapsed `<details>` that can be expanded. Tags and entities are never
+// split, so raw HTML and links stay intact.
+fn collapse_long_lines(html: &str, max: usize) -> String {
+    let mut collapsed = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        collapse_text(&rest[..start], max, &mut collapsed);
+        let end = start + tag_length(&rest[start..]);
+        collapsed.push_str(&rest[start..end]);
+        rest = &rest[end..];
+    }
+    collapse_text(rest, max, &mut collapsed);
+    collapsed
+}
+
+// Text between tags, line by line.
+fn collapse_text(text: &str, max: usize, collapsed: &mut String) {
+    for (n, line) in text.split('\n').enumerate() {
+        if n > 0 {
+            collapsed.push('\n');
+        }
+        let mut offsets = char_offsets(line);
+        match offsets.nth(max) {
+            Some(cut) => {
+                let more = 1 + offsets.count();
+                collapsed.push_str(&format!(
+                    "{}<details class=\"longLine\"><summary>… {more} more characters</summary>{}</details>",
+                    &line[..cut],
+                    &line[cut..]
+                ));
+            }
+            None => collapsed.push_str(line),
+        }
+    }
+}
+
+// Length of the tag `html` starts with, including attribute values with `>`.
+fn tag_length(html: &str) -> usize {
+    let mut quote = None;
+    for (i, c) in html.char_indices() {
+        match (quote, c) {
+            (None, '>') => return i + 1,
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(open), c) if c == open => quote = None,
+            _ => {}
+        }
+    }
+    html.len()
+}
+
+// Where the characters of `text` start, an entity like `&amp;` being one.
+fn char_offsets(text: &str) -> impl Iterator<Item = usize> + '_ {
+    let mut i = 0;
+    std::iter::from_fn(move || {
+        let c = text[i..].chars().next()?;
+        let start = i;
+        i += match text[i..].find(';') {
+            Some(end) if c == '&' && end <= 10 => end + 1,
+            _ => c.len_utf8(),
+        };
+        Some(start)
+    })
+}
+
+// Gives every heading of one note a unique id. comrak's own `header_ids`
+// extension links to the id without its prefix, so headings are rendered
+// here instead.
+struct HeadingIds<'a> {
+    config: &'a RenderConfig,
+    state: Mutex<(Anchorizer, usize)>,
+}
+
+impl<'a> HeadingIds<'a> {
+    fn new(config: &'a RenderConfig) -> Self {
+        HeadingIds {
+            config,
+            state: Mutex::new((Anchorizer::new(), 0)),
+        }
+    }
+
+    fn next_id(&self, heading: &HeadingMeta) -> String {
+        let (anchorizer, count) = &mut *self.state.lock().unwrap();
+        *count += 1;
+        let id = match self.config.heading_anchors {
+            HeadingAnchors::Numeric => count.to_string(),
+            _ => anchorizer.anchorize(heading.content.clone()),
+        };
+        format!("{}{}", self.config.heading_id_prefix, id)
+    }
+}
+
+impl HeadingAdapter for HeadingIds<'_> {
+    fn enter(
+        &self,
+        output: &mut dyn io::Write,
+        heading: &HeadingMeta,
+        _sourcepos: Option<Sourcepos>,
+    ) -> io::Result<()> {
+        let id = crate::escape_html(&self.next_id(heading));
+        write!(
+            output,
+            "<h{} id=\"{id}\"><a href=\"#{id}\" aria-hidden=\"true\" class=\"anchor\"></a>",
+            heading.level
+        )
+    }
+
+    fn exit(&self, output: &mut dyn io::Write, heading: &HeadingMeta) -> io::Result<()> {
+        writeln!(output, "</h{}>", heading.level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn html(markdown: &str) -> String {
+        md_to_html(markdown, &RenderConfig::default())
+    }
+
+    #[test]
+    fn duplicate_headings_get_distinct_ids() {
+        let html = html("## Intro\n\n## Intro\n\n## Intro");
+        assert!(html.contains("<h2 id=\"intro\">"));
+        assert!(html.contains("<h2 id=\"intro-1\">"));
+        assert!(html.contains("<h2 id=\"intro-2\">"));
+    }
+
+    #[test]
+    fn numeric_heading_ids_have_prefix() {
+        let config = RenderConfig {
+            heading_anchors: HeadingAnchors::Numeric,
+            heading_id_prefix: "h-".to_string(),
+            ..RenderConfig::default()
+        };
+        let html = md_to_html("# One\n\n# One", &config);
+        assert!(html.contains("<h1 id=\"h-1\"><a href=\"#h-1\""));
+        assert!(html.contains("<h1 id=\"h-2\"><a href=\"#h-2\""));
+    }
+
+    fn with_max_line_length(max: usize) -> RenderConfig {
+        RenderConfig {
+            max_line_length: Some(max),
+            ..RenderConfig::default()
+        }
+    }
+
+    #[test]
+    fn long_line_is_collapsed() {
+        let line = "a".repeat(100_000);
+        let html = md_to_html(&line, &with_max_line_length(80));
+        let expected = format!(
+            "<p>{}<details class=\"longLine\"><summary>… 99920 more characters</summary>{}</details></p>\n",
+            "a".repeat(80),
+            "a".repeat(99_920)
+        );
+        assert_eq!(html, expected);
+    }
+
+    #[test]
+    fn short_lines_are_left_alone() {
+        let markdown = "short line\nanother one";
+        assert_eq!(
+            md_to_html(markdown, &with_max_line_length(80)),
+            html(markdown)
+        );
+    }
+
+    #[test]
+    fn long_line_is_not_cut_inside_tags_or_entities() {
+        let url = format!("https://example.com/{}", "b".repeat(200));
+        let markdown = format!(
+            "[link]({url}) <span title=\"{}\">x</span> {}",
+            "c".repeat(200),
+            "&".repeat(20)
+        );
+        let html = md_to_html(&markdown, &with_max_line_length(10));
+        assert!(html.contains(&format!("<a href=\"{url}\">link</a>")));
+        assert!(html.contains(&format!("<span title=\"{}\">x</span>", "c".repeat(200))));
+        // 1 space and 9 entities before the cut
+        assert!(html.contains(&format!(
+            " {}<details class=\"longLine\"><summary>… 11 more characters</summary>",
+            "&amp;".repeat(9)
+        )));
+    }
+
+    #[test]
+    fn callout_types() {
+        for (marker, kind, title) in [
+            ("NOTE", "note", "Note"),
+            ("TIP", "tip", "Tip"),
+            ("IMPORTANT", "important", "Important"),
+            ("WARNING", "warning", "Warning"),
+            ("caution", "caution", "Caution"),
+        ] {
+            let html = html(&format!("> [!{marker}]\n> Text"));
+            assert_eq!(
+                html,
+                format!(
+                    "<blockquote class=\"callout\" data-callout=\"{kind}\">\n<p class=\"calloutTitle\">{title}</p>\n<p>Text</p>\n</blockquote>\n"
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn callout_with_title() {
+        let html = html("> [!WARNING] Mind the gap\n> Text");
+        assert!(html.starts_with(
+            "<blockquote class=\"callout\" data-callout=\"warning\">\n<p class=\"calloutTitle\">Mind the gap</p>\n<p>Text</p>"
+        ));
+    }
+
+    #[test]
+    fn bare_callout_marker() {
+        let html = html("> [!TIP]");
+        assert_eq!(
+            html,
+            "<blockquote class=\"callout\" data-callout=\"tip\">\n<p class=\"calloutTitle\">Tip</p>\n</blockquote>\n"
+        );
+    }
+
+    #[test]
+    fn unknown_marker_stays_blockquote() {
+        assert_eq!(
+            html("> [!FOO]\n> Text"),
+            "<blockquote>\n<p>[!FOO]\nText</p>\n</blockquote>\n"
+        );
+    }
+
+    #[test]
+    fn nested_callout() {
+        let html = html("> [!NOTE]\n> Outer\n>\n> > [!CAUTION]\n> > Inner");
+        assert!(html.starts_with(
+            "<blockquote class=\"callout\" data-callout=\"note\">\n<p class=\"calloutTitle\">Note</p>\n<p>Outer</p>\n"
+        ));
+        assert!(html.contains(
+            "<blockquote class=\"callout\" data-callout=\"caution\">\n<p class=\"calloutTitle\">Caution</p>\n<p>Inner</p>\n</blockquote>\n</blockquote>"
+        ));
+    }
+
+    #[test]
+    fn footnotes() {
+        let html = html("Text[^1].\n\n[^1]: The note.");
+        assert!(html.contains("<a href=\"#fn-1\" id=\"fnref-1\" data-footnote-ref>1</a>"));
+        assert!(html.contains("<section class=\"footnotes\" data-footnotes>"));
+        assert!(html.contains("<li id=\"fn-1\">\n<p>The note. <a href=\"#fnref-1\""));
+    }
+
+    #[test]
+    fn definition_lists() {
+        assert_eq!(
+            html("Term\n\n: Definition"),
+            "<dl><dt>Term</dt>\n<dd>\n<p>Definition</p>\n</dd>\n</dl>\n"
+        );
+    }
+
+    #[test]
+    fn highlighted_code_is_escaped() {
+        let config = RenderConfig {
+            syntax_theme: Some("InspiredGitHub".to_string()),
+            ..RenderConfig::default()
+        };
+        let html = md_to_html("```rust\nlet s = \"<span>\";\n```", &config);
+        assert!(html.contains("<code class=\"language-rust\"><span style="));
+        assert!(html.contains("&quot;&lt;span&gt;&quot;"));
+        assert!(!html.contains("<span>"));
+    }
+
+    #[test]
+    fn unhighlighted_code_is_escaped() {
+        let html = html("```rust\nlet s = \"<span>\";\n```");
+        assert_eq!(
+            html,
+            "<pre><code class=\"language-rust\">let s = &quot;&lt;span&gt;&quot;;\n</code></pre>\n"
+        );
+    }
+
+    #[test]
+    fn inline_math_is_not_emphasis() {
+        assert_eq!(
+            html("$a_1 * b_2 * c$ and $x_i$"),
+            "<p><span data-math-style=\"inline\">a_1 * b_2 * c</span> and <span data-math-style=\"inline\">x_i</span></p>\n"
+        );
+    }
+
+    #[test]
+    fn display_math_is_not_emphasis() {
+        assert_eq!(
+            html("$$\\sum_{i=1}^n x_i * y_i$$"),
+            "<p><span data-math-style=\"display\">\\sum_{i=1}^n x_i * y_i</span></p>\n"
+        );
+    }
+
+    #[test]
+    fn code_math_is_not_emphasis() {
+        assert_eq!(
+            html("$`a_1 * b_2 * c`$"),
+            "<p><code data-math-style=\"inline\">a_1 * b_2 * c</code></p>\n"
+        );
+    }
+
+    // Markdown covering every extension, including malformed input, for the
+    // checks below that have to hold for any input.
+    const SAMPLES: &[&str] = &[
+        "",
+        "plain text",
+        "# Heading\n\n## Heading\n\ntext",
+        "*emphasis* **strong** ***both*** ~~struck~~ ^sup^",
+        "*unclosed **emphasis\n\nand `unclosed code",
+        "[link](https://example.com) ![image](/a.png) <https://example.com>",
+        "[unclosed link(https://example.com",
+        "- one\n  - nested\n    1. deeper\n- [ ] task\n- [x] done",
+        "> quote\n>\n> > nested quote\n\n> [!WARNING] Careful\n> text",
+        "| a | b |\n|---|---|\n| 1 | *2* |\n| 3",
+        "```rust\nfn main() { println!(\"<b>\"); }\n```",
+        "```\nunclosed fence",
+        "text[^1]\n\n[^1]: note with **bold**",
+        "Term\n\n: definition",
+        "$a_1 * b_2$ and $$\\sum_{i} x^2$$",
+        "a < b > c & d \"quoted\" 'single'",
+        "line  \nbreak\n\n---\n\nafter rule",
+        "<script>alert(1)</script>",
+        "<SCRIPT src=\"https://example.com/x.js\"></SCRIPT>",
+        "before <script>alert(1)</script> after",
+        "<style>body { display: none }</style>",
+        "<iframe src=\"https://example.com\"></iframe>",
+        "<textarea><title><xmp><noembed><noframes><plaintext>",
+        "*a <script>alert(1)</script> b*",
+    ];
+
+    // Elements that have no closing tag.
+    const VOID_ELEMENTS: &[&str] = &[
+        "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source",
+        "track", "wbr",
+    ];
+
+    // Every opening tag is closed in the right order, ignoring void elements.
+    fn assert_balanced(markdown: &str, html: &str) {
+        let tag = regex::Regex::new(r"<(/?)([a-zA-Z][a-zA-Z0-9]*)[^>]*>").unwrap();
+        let mut open = Vec::new();
+        for caps in tag.captures_iter(html) {
+            let name = caps[2].to_lowercase();
+            if VOID_ELEMENTS.contains(&name.as_str()) {
+                continue;
+            }
+            if caps[1].is_empty() {
+                open.push(name);
+            } else {
+                assert_eq!(
+                    open.pop().as_deref(),
+                    Some(name.as_str()),
+                    "unbalanced </{name}> for {markdown:?}:\n{html}"
+                );
+            }
+        }
+        assert!(
+            open.is_empty(),
+            "unclosed {open:?} for {markdown:?}:\n{html}"
+        );
+    }
+
+    #[test]
+    fn rendered_tags_are_balanced() {
+        for markdown in SAMPLES {
+            assert_balanced(markdown, &html(markdown));
+            assert_balanced(markdown, &md_to_html(markdown, &with_max_line_length(10)));
+        }
+    }
+
+    #[test]
+    fn dangerous_tags_are_escaped() {
+        let dangerous = [
+            "<script",
+            "<style",
+            "<iframe",
+            "<textarea",
+            "<title",
+            "<xmp",
+        ];
+        for markdown in SAMPLES {
+            for config in [RenderConfig::default(), with_max_line_length(10)] {
+                let html = md_to_html(markdown, &config).to_lowercase();
+                for tag in dangerous {
+                    assert!(!html.contains(tag), "{tag} in {markdown:?}:\n{html}");
+                }
+            }
+        }
+        assert!(html("<script>alert(1)</script>").contains("&lt;script>alert(1)&lt;/script>"));
+    }
+}
@@ -0,0 +1,152 @@
+// Log output: human-readable text, or one JSON object per line for log
+// collectors such as Loki or Elasticsearch.
+
+use chrono::{SecondsFormat, Utc};
+use serde_json::{json, Map, Value};
+use std::{env, fmt, str::FromStr};
+use tracing::{
+    field::{Field, Visit},
+    level_filters::LevelFilter,
+    Event, Subscriber,
+};
+use tracing_subscriber::{
+    filter::Targets,
+    fmt::{
+        format::Writer, FmtContext, FormatEvent, FormatFields, FormattedFields,
+        Subscriber as FmtSubscriber,
+    },
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line with `timestamp`, `level`, `target`, `fields` and `spans`
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Trace => LevelFilter::TRACE,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Error => LevelFilter::ERROR,
+        }
+    }
+}
+
+/// Installs the global logger. Without a `level`, `RUST_LOG` decides what is
+/// logged (e.g. `debug` or `textpod=debug`), as before, and else `info`.
+pub fn init(format: LogFormat, level: Option<LogLevel>) {
+    let targets = match level {
+        Some(level) => Targets::new().with_default(level),
+        None => env::var("RUST_LOG")
+            .ok()
+            .and_then(|filter| match Targets::from_str(&filter) {
+                Ok(targets) => Some(targets),
+                Err(e) => {
+                    eprintln!("Ignoring RUST_LOG={filter:?}: {e}");
+                    None
+                }
+            })
+            .unwrap_or_else(|| Targets::new().with_default(LevelFilter::INFO)),
+    };
+
+    let builder = FmtSubscriber::builder().with_max_level(LevelFilter::TRACE);
+    match format {
+        LogFormat::Text => builder.finish().with(targets).init(),
+        LogFormat::Json => builder
+            .with_ansi(false)
+            .event_format(JsonFormat)
+            .finish()
+            .with(targets)
+            .init(),
+    }
+}
+
+// tracing-subscriber's own JSON output needs a crate that isn't available
+// here, so events are formatted with serde_json instead.
+struct JsonFormat;
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut fields = JsonFields(Map::new());
+        event.record(&mut fields);
+
+        // span fields are kept as formatted by `N`, e.g. `id=3 path="/notes"`
+        let spans = ctx
+            .event_scope()
+            .into_iter()
+            .flat_map(|scope| scope.from_root())
+            .map(|span| {
+                let extensions = span.extensions();
+                let fields = extensions
+                    .get::<FormattedFields<N>>()
+                    .map_or("", |fields| fields.fields.as_str());
+                json!({ "name": span.name(), "fields": fields })
+            })
+            .collect::<Vec<_>>();
+
+        let metadata = event.metadata();
+        let line = json!({
+            "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            "level": metadata.level().as_str(),
+            "target": metadata.target(),
+            "fields": fields.0,
+            "spans": spans,
+        });
+        writeln!(writer, "{line}")
+    }
+}
+
+struct JsonFields(Map<String, Value>);
+
+impl Visit for JsonFields {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), json!(format!("{value:?}")));
+    }
+}
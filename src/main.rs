@@ -1,36 +1,95 @@
+mod analytics;
+mod conv;
+mod downloader;
+mod logging;
+mod notes_dir;
+mod obsidian;
+mod rate_limit;
+mod sniff;
+
+use analytics::{jaccard_similarity, word_frequency, word_set, StopWords};
 use axum::{
-    extract::{DefaultBodyLimit, Multipart, Path, State},
-    http::StatusCode,
-    response::{Html, IntoResponse},
-    routing::{get, post},
+    body::{Body, Bytes},
+    extract::{
+        multipart::MultipartError, rejection::JsonRejection, ConnectInfo, DefaultBodyLimit,
+        Multipart, Path, Query, Request, State,
+    },
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Response,
+    },
+    routing::{delete, get, post, put},
     Json, Router,
 };
-use base64::{display::Base64Display, engine::general_purpose::STANDARD};
-use chrono::Local;
-use clap::Parser;
-use comrak::{markdown_to_html, Options};
-use serde::{Deserialize, Serialize};
+use base64::{
+    display::Base64Display,
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, Days, FixedOffset, Local, NaiveDate, NaiveDateTime, NaiveTime, Utc,
+};
+use clap::{Parser, ValueEnum};
+use conv::{extract_links, md_to_html, HeadingAnchors, RenderConfig};
+use downloader::{
+    download_audio, download_ftp, download_video, download_webpage, is_audio_url, is_ftp_url,
+    without_credentials, DownloadQueue, DownloadStatus, DownloadTracker, QueueStatus, RetryPolicy,
+};
+use futures_util::{
+    future,
+    stream::{self, Stream, StreamExt},
+};
+use logging::{LogFormat, LogLevel};
+use rate_limit::RateLimiter;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap},
     env,
     fs::{self},
+    hash::{BuildHasher, DefaultHasher, Hash, Hasher, RandomState},
     io::Write,
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     path::PathBuf,
     process,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    process::Command,
+    sync::{broadcast, RwLock, Semaphore},
+};
+use tokio::{
+    spawn,
+    task::JoinHandle,
+    time::{sleep, timeout},
+};
+use tower_http::{
+    cors::{AllowOrigin, CorsLayer},
+    services::ServeDir,
+    timeout::TimeoutLayer,
 };
-use tokio::process::Command;
-use tokio::spawn;
-use tower_http::services::ServeDir;
-use tracing::{error, info};
-use tracing_subscriber;
+use tracing::{error, info, info_span, warn, Instrument};
 
 const INDEX_HTML: &str = include_str!("index.html");
+const NOTES_HTML: &str = include_str!("notes.html");
 const FAVICON_SVG: &[u8] = include_bytes!("favicon.svg");
+const SERVICE_WORKER_JS: &str = include_str!("sw.js");
+/// Sizes of the app icons listed in the web app manifest
+const ICON_SIZES: [u16; 2] = [192, 512];
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    action: Option<Action>,
     /// Change to DIR before doing anything
     #[arg(short = 'C', long, value_name = "DIR")]
     base_directory: Option<PathBuf>,
@@ -40,360 +99,5811 @@ struct Args {
     /// Listen address for the server
     #[arg(short, long, default_value = "127.0.0.1")]
     listen: String,
-    /// Save notes in FILE
-    #[arg(short = 'f', long, value_name = "FILE", default_value = "notes.md")]
-    notes_file: PathBuf,
+    /// Save notes in FILE [default: notes.md, notes.json with `--format json`, or
+    /// the notes directory with `--format dir`]
+    #[arg(short = 'f', long, value_name = "FILE")]
+    notes_file: Option<PathBuf>,
+    /// Format of the notes file
+    #[arg(long, value_enum, default_value_t = NotesFormat::Markdown)]
+    format: NotesFormat,
+    /// Show the Markdown files in the Obsidian vault at PATH as notes, without allowing any changes
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["notes_file", "format"])]
+    obsidian_vault: Option<PathBuf>,
+    /// Commit the notes directory to git after every change
+    #[arg(long)]
+    git_commit: bool,
+    /// Number the first note N instead of 0, e.g. to keep ids of several instances apart
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    id_offset: usize,
+    /// Only answer requests for HOST (repeatable); recommended when listening on a LAN
+    #[arg(long = "allowed-host", value_name = "HOST")]
+    allowed_hosts: Vec<String>,
+    /// Ask for this user name when `--password` is set
+    #[arg(long, value_name = "NAME", default_value = "admin")]
+    username: String,
+    /// Require HTTP Basic Auth with this password (use TLS, e.g. a reverse proxy, on untrusted networks)
+    #[arg(long)]
+    password: Option<String>,
+    /// Serve notes without allowing any changes, e.g. on a shared network
+    #[arg(long)]
+    read_only: bool,
+    /// Let web pages from ORIGIN (e.g. `https://example.com`, or `*` for any) call the API (repeatable)
+    #[arg(long = "allow-origin", value_name = "ORIGIN")]
+    allow_origins: Vec<String>,
+    /// Reload the notes when the notes file is changed by another program, e.g. an editor
+    #[arg(long)]
+    watch: bool,
+    /// Save a copy of the notes file in DIR every `--backup-interval` hours
+    #[arg(long, value_name = "DIR")]
+    backup_dir: Option<PathBuf>,
+    /// Hours between backups
+    #[arg(long, value_name = "HOURS", default_value_t = 24, value_parser = clap::value_parser!(u64).range(1..))]
+    backup_interval: u64,
+    /// Tag new notes matching REGEX with TAG, e.g. `https?://=link` (repeatable)
+    #[arg(long = "auto-tag", value_name = "REGEX=TAG", value_parser = parse_auto_tag)]
+    auto_tags: Vec<AutoTag>,
+    /// Only accept uploads whose content is of these MIME types or type prefixes,
+    /// e.g. `image/,text/,application/pdf` [default: any]
+    #[arg(long, value_name = "TYPES", value_delimiter = ',')]
+    allowed_types: Vec<String>,
+    /// Reject uploads once there are N attachments (including downloaded webpages)
+    #[arg(long, value_name = "N")]
+    max_attachments: Option<usize>,
+    /// Reject uploads that would make the attachments take up more than BYTES
+    #[arg(long, value_name = "BYTES")]
+    max_attachment_storage: Option<u64>,
+    /// Name of the multipart field holding the file in uploads
+    #[arg(long, value_name = "NAME", default_value = "file")]
+    upload_field: String,
+    /// What to do with links starting with +: save a local copy, fetch a preview card,
+    /// fetch a preview and save a copy only when the page has no preview, or save the
+    /// video on the page and a copy only when there is none
+    #[arg(long, value_enum, default_value_t = LinkMode::Snapshot)]
+    link_mode: LinkMode,
+    /// Download links again even if a local copy of the same URL already exists
+    #[arg(long)]
+    refresh_snapshots: bool,
+    /// How `GET /notes/search` matches the query against notes
+    #[arg(long, value_enum, default_value_t = SearchMode::Substring)]
+    search_mode: SearchMode,
+    /// Format of note timestamps (strftime syntax), used when reading and writing notes
+    #[arg(long, value_name = "FORMAT", default_value = "%Y-%m-%d %H:%M:%S")]
+    timestamp_format: String,
+    /// Write new timestamps in this time zone, `UTC` or an offset like `+05:30`,
+    /// instead of the system's local time
+    #[arg(long, value_name = "ZONE", value_parser = parse_timezone)]
+    timezone: Option<FixedOffset>,
+    /// Download at most N links at the same time
+    #[arg(long, value_name = "N", default_value_t = 4)]
+    download_concurrency: usize,
+    /// Give up downloading a link (local copy or preview) after this many seconds
+    #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+    download_timeout: u64,
+    /// Extra arguments for monolith, e.g. `--monolith-args "--no-js --timeout 30"` (repeatable)
+    #[arg(long, value_name = "ARGS", value_parser = parse_monolith_args, allow_hyphen_values = true)]
+    monolith_args: Vec<String>,
+    /// yt-dlp format selection (`-f`) for videos saved with `--link-mode video`, e.g.
+    /// `bestvideo[height<=1080]+bestaudio/best`
+    #[arg(long, value_name = "FORMAT", default_value = "bestvideo+bestaudio/best", value_parser = parse_yt_dlp_format)]
+    yt_dlp_format: String,
+    /// Also save links to pages on HOST or its subdomains as audio, besides SoundCloud,
+    /// Bandcamp, Mixcloud, Apple Podcasts and Audioboom, e.g. `podbean.com` (repeatable)
+    #[arg(long = "audio-host", value_name = "HOST", value_parser = parse_audio_host)]
+    audio_hosts: Vec<String>,
+    /// Try failed downloads this many more times
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    download_retries: u32,
+    /// Seconds to wait before retrying a failed download; doubles with every retry
+    #[arg(long, value_name = "SECONDS", default_value_t = 5)]
+    download_retry_delay: u64,
+    /// Abort requests taking longer than this many seconds with 408 Request Timeout (uploads excepted)
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    request_timeout: u64,
+    /// Refuse notes whose content is longer than this many bytes
+    #[arg(long, value_name = "BYTES", default_value_t = 1024 * 1024)]
+    max_note_size: usize,
+    /// Allow each client to create at most N notes per minute (0 for no limit)
+    #[arg(long, value_name = "N", default_value_t = 60)]
+    rate_limit_notes: usize,
+    /// Allow each client at most N uploads per minute (0 for no limit)
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    rate_limit_uploads: usize,
+    /// Take the client's address for rate limits from the X-Forwarded-For header set by a reverse proxy
+    #[arg(long)]
+    behind_proxy: bool,
+    /// Header carrying the id of a request, taken from the request or else generated, and
+    /// added to the response, e.g. `X-Correlation-ID`
+    #[arg(long, value_name = "NAME", default_value = "x-request-id")]
+    request_id_header: HeaderName,
+    /// Log as human-readable text or as one JSON object per line
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    /// Log messages of this level and above; defaults to `RUST_LOG`, else `info`
+    #[arg(long, value_enum)]
+    log_level: Option<LogLevel>,
+    /// Allow saving when no notes are left, emptying a notes file that had notes
+    #[arg(long)]
+    allow_empty_overwrite: bool,
+    /// Name of the app when installed to a home screen
+    #[arg(long, value_name = "NAME", default_value = "Textpod")]
+    app_name: String,
+    /// Color of the browser UI and of the installed app's icon
+    #[arg(long, value_name = "COLOR", default_value = "#dedcd1")]
+    theme_color: String,
+    /// Background color of the installed app's splash screen
+    #[arg(long, value_name = "COLOR", default_value = "#ffffff")]
+    background_color: String,
+    /// Collapse lines longer than N characters when rendering notes, to be expanded on click (the saved note is kept intact)
+    #[arg(long, value_name = "N")]
+    max_line_length: Option<usize>,
+    /// How ids for heading anchors are generated
+    #[arg(long, value_enum, default_value_t = HeadingAnchors::Github)]
+    heading_anchors: HeadingAnchors,
+    /// Prefix added to every heading id, e.g. `h-`
+    #[arg(long, value_name = "PREFIX", default_value = "")]
+    heading_id_prefix: String,
+    /// Theme for highlighting code blocks, e.g. `base16-ocean.dark`, or `none` for plain code
+    #[arg(long, value_name = "NAME", default_value = "InspiredGitHub", value_parser = parse_syntax_theme)]
+    syntax_theme: String,
+}
+
+/// An `--auto-tag` rule.
+#[derive(Clone)]
+struct AutoTag {
+    pattern: Regex,
+    tag: String,
+}
+
+// The tag can't contain `=`, so everything before the last one is the regex.
+fn parse_auto_tag(rule: &str) -> Result<AutoTag, String> {
+    let (pattern, tag) = rule
+        .rsplit_once('=')
+        .ok_or("expected REGEX=TAG, e.g. TODO=todo")?;
+    if !valid_tag(tag) {
+        return Err(format!("invalid tag {tag:?}, use letters, digits, - and _"));
+    }
+    let pattern = Regex::new(pattern).map_err(|e| e.to_string())?;
+
+    Ok(AutoTag {
+        pattern,
+        tag: tag.to_string(),
+    })
+}
+
+// Without a time zone database only UTC and fixed offsets are supported.
+fn parse_timezone(zone: &str) -> Result<FixedOffset, String> {
+    if zone.eq_ignore_ascii_case("utc") || zone == "Z" {
+        return Ok(FixedOffset::east_opt(0).unwrap());
+    }
+    zone.parse()
+        .map_err(|_| "expected UTC or an offset like +05:30".to_string())
+}
+
+// Checks that `args` can be split into words like a shell would. They are
+// split when starting the server, see `Args::monolith_args`.
+fn parse_monolith_args(args: &str) -> Result<String, String> {
+    shell_words::split(args)
+        .map(|_| args.to_string())
+        .map_err(|e| e.to_string())
+}
+
+// Format selectors never contain quotes, spaces or the characters that chain
+// shell commands, so those are rejected to catch pasted command lines. (Their
+// filters do use `<>?*$~!`, e.g. `[height<=?720]`.)
+fn parse_yt_dlp_format(format: &str) -> Result<String, String> {
+    match format
+        .chars()
+        .find(|c| c.is_whitespace() || c.is_control() || ";|&`\\'\"".contains(*c))
+    {
+        Some(c) => Err(format!("{c:?} is not allowed in a format")),
+        None if format.is_empty() => Err("the format is empty".to_string()),
+        None => Ok(format.to_string()),
+    }
+}
+
+// Hosts are compared in lowercase, without a port.
+fn parse_audio_host(host: &str) -> Result<String, String> {
+    let host = host.trim().trim_end_matches('.').to_lowercase();
+    if host.is_empty() || host.contains(|c: char| c == '/' || c == ':' || c.is_whitespace()) {
+        return Err(format!("{host:?} is not a host name like `podbean.com`"));
+    }
+    Ok(host)
+}
+
+fn parse_syntax_theme(name: &str) -> Result<String, String> {
+    let mut themes = conv::syntax_themes();
+    if name == "none" || themes.iter().any(|theme| theme == name) {
+        return Ok(name.to_string());
+    }
+    themes.sort();
+    Err(format!(
+        "unknown theme, expected none or one of: {}",
+        themes.join(", ")
+    ))
+}
+
+// Formats `time` in `timezone`, or in local time if there is none.
+fn format_timestamp(time: DateTime<Utc>, timezone: Option<FixedOffset>, format: &str) -> String {
+    match timezone {
+        Some(timezone) => time.with_timezone(&timezone).format(format).to_string(),
+        None => time.with_timezone(&Local).format(format).to_string(),
+    }
+}
+
+#[derive(clap::Subcommand)]
+enum Action {
+    /// Add the notes in FILE (one JSON object like {"timestamp": "...", "content": "..."} per line) and exit
+    Import {
+        /// Newline-delimited JSON file to read the notes from
+        #[arg(long, value_name = "FILE")]
+        file: PathBuf,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum NotesFormat {
+    /// Notes separated by `---` lines, see `note_block`
+    Markdown,
+    /// A JSON array of notes
+    Json,
+    /// A directory with one Markdown file per note, named after its id
+    Dir,
+    /// An Obsidian vault, read-only; set with `--obsidian-vault`
+    #[value(skip)]
+    Obsidian,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum SearchMode {
+    /// Notes containing the query, ignoring case
+    Substring,
+    /// Notes matching the query as a regular expression
+    Regex,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum LinkMode {
+    Snapshot,
+    Preview,
+    Auto,
+    Video,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct Note {
+    /// Position of the note in the notes file plus `--id-offset`
+    #[serde(default)]
+    id: usize,
+    /// Identifies the note for as long as textpod runs, unlike `id` also after
+    /// earlier notes are deleted. Never saved.
+    #[serde(skip, default = "next_note_key")]
+    key: u64,
     timestamp: String,
+    /// When the content was last edited, if ever
+    #[serde(default)]
+    modified: Option<String>,
     content: String,
+    #[serde(default)]
     html: String,
+    /// Notes with a higher priority come first when sorting by priority
+    #[serde(default)]
+    priority: Option<i32>,
+    /// OpenGraph previews of links saved with `--link-mode preview` or `auto`
+    #[serde(default)]
+    previews: Vec<LinkPreview>,
+    /// CSS classes added to the note's element, see `valid_class_name`
+    #[serde(default)]
+    classes: Vec<String>,
+    /// Tags given when the note was created, including those of `--auto-tag` rules
+    #[serde(default)]
+    tags: Vec<String>,
+    /// Pinned notes are shown at the top
+    #[serde(default)]
+    pinned: bool,
+    /// Archived notes are left out of `GET /notes` unless asked for
+    #[serde(default)]
+    archived: bool,
+    /// Unique name for readable URLs, see `unique_slug`
+    #[serde(default)]
+    slug: Option<String>,
+    /// Checksum of the content, see `content_checksum`. Set by `render`; the
+    /// one in the notes file is only compared against when loading.
+    #[serde(default)]
+    checksum: Option<String>,
+    /// Derived from the content by `render`, never read from the notes file
+    #[serde(skip_deserializing)]
+    word_count: usize,
+    /// At 200 words per minute
+    #[serde(skip_deserializing)]
+    reading_time_seconds: u64,
 }
 
-#[derive(Clone)]
-struct AppState {
-    html: String,
-    notes: Arc<Mutex<Vec<Note>>>,
-    notes_file: PathBuf,
+impl Note {
+    /// A note without metadata; `html` is left empty for `render` to fill in.
+    fn new(id: usize, timestamp: String, content: String) -> Self {
+        Note {
+            id,
+            key: next_note_key(),
+            timestamp,
+            modified: None,
+            content,
+            html: String::new(),
+            priority: None,
+            previews: Vec::new(),
+            classes: Vec::new(),
+            tags: Vec::new(),
+            pinned: false,
+            archived: false,
+            slug: None,
+            checksum: None,
+            word_count: 0,
+            reading_time_seconds: 0,
+        }
+    }
+
+    /// Warns when the content doesn't match the checksum it was saved with.
+    fn check_checksum(&self) {
+        if let Some(saved) = &self.checksum {
+            let actual = content_checksum(&self.content);
+            if *saved != actual {
+                warn!(
+                    "Note #{} ({}) doesn't match its checksum ({} instead of {}); it was changed by another program or the file is corrupted",
+                    self.id, self.timestamp, actual, saved
+                );
+            }
+        }
+    }
+
+    /// Updates everything derived from the content after it changed.
+    fn render(&mut self, config: &RenderConfig) {
+        self.html = render_note(self, config);
+        self.checksum = Some(content_checksum(&self.content));
+        self.word_count = self.content.split_whitespace().count();
+        self.reading_time_seconds = (self.word_count as u64 * 60).div_ceil(WORDS_PER_MINUTE);
+    }
 }
 
-const CONTENT_LENGTH_LIMIT: usize = 500 * 1024 * 1024; // allow uploading up to 500mb files... overkill?
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct LinkPreview {
+    url: String,
+    title: Option<String>,
+    description: Option<String>,
+    image: Option<String>,
+}
 
-#[tokio::main]
-async fn main() {
-    tracing_subscriber::fmt::init();
+/// Body accepted by `POST /notes`: either a bare JSON string (`"some text"`)
+/// or an object with a `content` field (`{"content": "some text"}`) and
+/// optional `tags`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NoteBody {
+    Text(String),
+    Object {
+        content: String,
+        #[serde(default)]
+        tags: Vec<String>,
+    },
+}
 
-    let args = Args::parse();
+impl NoteBody {
+    fn into_content(self) -> String {
+        self.into_parts().0
+    }
 
-    if let Some(path) = args.base_directory {
-        if let Err(e) = env::set_current_dir(&path) {
-            error!("could not change directory to {}: {e}", path.display());
-            process::exit(1);
+    fn into_parts(self) -> (String, Vec<String>) {
+        match self {
+            NoteBody::Text(content) => (content, Vec::new()),
+            NoteBody::Object { content, tags } => (content, tags),
         }
     }
+}
 
-    if let Err(e) = fs::create_dir_all("attachments") {
-        error!(
-            "could not create attachments directory in {}: {e}",
-            env::current_dir().unwrap().display()
-        );
-        process::exit(1);
-    }
+/// A note being composed. Drafts only live in memory until they are published.
+#[derive(Clone, Serialize)]
+struct Draft {
+    id: usize,
+    created: String,
+    updated: String,
+    content: String,
+}
 
-    let favicon = Base64Display::new(FAVICON_SVG, &STANDARD);
-    let html = INDEX_HTML.replace(
-        "{{FAVICON}}",
-        format!("data:image/svg+xml;base64,{favicon}").as_str(),
-    );
+#[derive(Default)]
+struct Drafts {
+    next_id: usize,
+    drafts: BTreeMap<usize, Draft>,
+}
 
-    let notes = Arc::new(Mutex::new(load_notes(&args.notes_file)));
+#[derive(Deserialize)]
+struct NotesQuery {
+    /// Cursor returned as `next_cursor` by the previous page
+    after: Option<String>,
+    /// Number of notes to skip, instead of `after`
+    offset: Option<usize>,
+    limit: Option<usize>,
+    /// Only notes with this tag
+    tag: Option<String>,
+    /// Only notes created on or after this date (`2024-01-01`) or time (`2024-01-01T12:00:00`)
+    from: Option<String>,
+    /// Only notes created on or before this date or time
+    to: Option<String>,
+    #[serde(default)]
+    include_archived: bool,
+    sort: Option<NotesSort>,
+}
 
-    let state = AppState {
-        html,
-        notes,
-        notes_file: args.notes_file,
-    };
+#[derive(Deserialize)]
+struct ExportQuery {
+    #[serde(default)]
+    format: ExportFormat,
+}
 
-    let app = Router::new()
-        .route("/", get(index))
-        .route("/notes", get(get_notes).post(save_note))
-        .route(
-            "/notes/:index",
-            get(get_note_by_index).delete(delete_note_by_index),
-        ) // TODO PUT/PATCH
-        .route("/upload", post(upload_file))
-        .layer(DefaultBodyLimit::max(CONTENT_LENGTH_LIMIT))
-        .nest_service("/attachments", ServeDir::new("attachments"))
-        .with_state(state);
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ExportFormat {
+    #[default]
+    Markdown,
+    Json,
+}
 
-    let server_details = format!("{}:{}", args.listen, args.port);
-    let addr: SocketAddr = server_details
-        .parse()
-        .expect("Unable to parse socket address");
-    info!("Starting server on http://{}", addr);
+/// A note in `GET /notes/export?format=json`; the HTML is left out as it is
+/// derived from the content.
+#[derive(Serialize)]
+struct ExportedNote<'a> {
+    id: usize,
+    timestamp: &'a str,
+    content: &'a str,
+    tags: &'a [String],
+}
 
-    match tokio::net::TcpListener::bind(&addr).await {
-        Ok(listener) => {
-            if let Err(e) = axum::serve(listener, app).await {
-                error!("Server error: {}", e);
-            }
-        }
-        Err(e) => {
-            error!("Failed to bind to address {}: {}", addr, e);
-        }
-    }
+#[derive(Deserialize)]
+struct RandomNoteQuery {
+    /// Only notes with this tag
+    tag: Option<String>,
 }
 
-fn load_notes(file: &PathBuf) -> Vec<Note> {
-    if let Ok(content) = fs::read_to_string(file) {
-        content
-            .split("\n\n---\n\n")
-            .filter(|s| !s.trim().is_empty())
-            .map(|block| {
-                let parts: Vec<&str> = block.splitn(2, '\n').collect();
-                let (timestamp, content) = match parts.as_slice() {
-                    [timestamp, content] => {
-                        (timestamp.trim().to_string(), content.trim().to_string())
-                    }
-                    _ => (
-                        Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
-                        block.to_string(),
-                    ),
-                };
+#[derive(Deserialize)]
+struct DuplicatesQuery {
+    /// Also group notes whose word sets are at least this similar, see
+    /// `jaccard_similarity`
+    threshold: Option<f64>,
+    /// Which note of each group `DELETE /notes/duplicates` keeps
+    #[serde(default)]
+    keep: Keep,
+}
 
-                let html = md_to_html(&content);
-                Note {
-                    timestamp,
-                    content: content.to_string(),
-                    html,
-                }
-            })
-            .collect()
-    } else {
-        Vec::new()
-    }
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Keep {
+    #[default]
+    Oldest,
+    Newest,
 }
 
-// route / (root)
-async fn index(State(state): State<AppState>) -> Html<String> {
-    Html(state.html)
+#[derive(Deserialize)]
+struct AnalyticsQuery {
+    #[serde(default)]
+    stop_words: StopWords,
 }
 
-// GET /notes
-async fn get_notes(State(state): State<AppState>) -> Json<Vec<Note>> {
-    let notes = state.notes.lock().unwrap();
-    Json(notes.iter().cloned().collect::<Vec<_>>())
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
 }
 
-// GET /notes/:index
-async fn get_note_by_index(
-    State(state): State<AppState>,
-    Path(index): Path<usize>,
-) -> Result<impl IntoResponse, (StatusCode, String)> {
-    let notes = state.notes.lock().unwrap();
-    if index >= notes.len() {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            format!("request for non-existent note #{index}"),
-        ));
-    }
+/// Inclusive date range of `GET /notes/timeline`, as `YYYY-MM-DD`
+#[derive(Deserialize)]
+struct TimelineQuery {
+    from: Option<String>,
+    to: Option<String>,
+}
 
-    return Ok(Json(notes.iter().collect::<Vec<_>>()[index].clone()));
+#[derive(Serialize)]
+struct TimelineDay {
+    /// `YYYY-MM-DD`
+    date: String,
+    notes: Vec<Note>,
 }
 
-// DELETE /notes/:index
-async fn delete_note_by_index(
-    State(state): State<AppState>,
-    Path(index): Path<usize>,
-) -> Result<impl IntoResponse, (StatusCode, String)> {
-    let mut notes = state.notes.lock().unwrap();
-    if index >= notes.len() {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            format!("request for non-existent note #{index}"),
-        ));
-    }
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum NotesSort {
+    /// Oldest first, pinned or not; without a sort pinned notes come first
+    CreatedAsc,
+    /// Newest first
+    CreatedDesc,
+    /// Most recently edited first; notes that were never edited count as
+    /// edited when they were created
+    ModifiedDesc,
+    /// Highest priority first, notes without a priority last
+    Priority,
+    /// Pinned notes first, oldest first, then the others, newest first
+    Pinned,
+}
 
-    notes.remove(index);
+/// Body of `PATCH /notes/:id`; fields that are left out stay unchanged.
+#[derive(Deserialize)]
+struct NotePatch {
+    /// `null` removes the priority
+    #[serde(default, deserialize_with = "deserialize_some")]
+    priority: Option<Option<i32>>,
+    /// Replaces all classes, `[]` removes them
+    classes: Option<Vec<String>>,
+    /// Text added to the end of the content, on a new line
+    append: Option<String>,
+}
 
-    // Update the notes file
-    let content = notes
-        .iter()
-        .map(|note| format!("{}\n{}\n\n---\n\n", note.timestamp, note.content))
-        .collect::<String>();
+#[derive(Serialize)]
+struct NotesPage {
+    notes: Vec<Note>,
+    /// Pass as `after` to get the next page, absent on the last page
+    next_cursor: Option<String>,
+    /// Number of notes on all pages
+    total: usize,
+    /// Position of the first note of the page
+    offset: usize,
+    limit: usize,
+}
 
-    if let Err(e) = fs::write(&state.notes_file, content) {
-        return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+#[derive(Serialize)]
+struct NoteStats {
+    total_notes: usize,
+    /// Whitespace-separated words in all notes
+    total_words: usize,
+    /// Timestamps of the oldest and newest notes, if there are any
+    oldest_note: Option<String>,
+    newest_note: Option<String>,
+    content_bytes: usize,
+    html_bytes: usize,
+    notes: Vec<NoteSize>,
+    attachments_bytes: u64,
+    /// Number of files in `attachments`, including downloaded webpages
+    total_attachments: usize,
+    /// Disk usage per subdirectory of `attachments`; files directly in it are counted as "uploads"
+    attachments: AttachmentsUsage,
+}
+
+#[derive(Serialize)]
+struct NoteSize {
+    id: usize,
+    content_bytes: usize,
+    html_bytes: usize,
+}
+
+/// Sent to `GET /events` subscribers when a note changes.
+#[derive(Clone, Serialize)]
+struct NoteEvent {
+    event: NoteEventKind,
+    note_id: usize,
+}
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum NoteEventKind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+impl NoteEventKind {
+    fn name(self) -> &'static str {
+        match self {
+            NoteEventKind::Created => "created",
+            NoteEventKind::Updated => "updated",
+            NoteEventKind::Deleted => "deleted",
+        }
     }
+}
 
-    info!("Note deleted: {}", index);
+#[derive(Serialize)]
+struct AttachmentInfo {
+    name: String,
+    /// URL of the file, e.g. `/attachments/webpages/example.html`
+    path: String,
+    size_bytes: u64,
+    /// When the file was saved, in `--timestamp-format`
+    created_at: String,
+    /// Guessed from the file extension
+    mime_type: String,
+}
 
-    // TODO return the deleted note, maybe?
-    return Ok(StatusCode::NO_CONTENT);
+/// Response of `DELETE /api/attachments/:filename` when notes still link to
+/// the deleted file.
+#[derive(Serialize)]
+struct DeletedAttachment {
+    deleted: String,
+    warning: String,
+    /// Ids of the notes linking to the file
+    linked_from: Vec<usize>,
 }
 
-// POST /notes
-async fn save_note(
-    State(state): State<AppState>,
-    Json(content): Json<String>,
-) -> Result<(), StatusCode> {
-    let mut content = content.clone();
+#[derive(Serialize)]
+struct Backup {
+    name: String,
+    /// When the backup was written, in `--timestamp-format`
+    timestamp: String,
+    size_bytes: u64,
+}
 
-    // Replace "---" with "<hr>" in the content
-    content = content.replace("---", "<hr>");
-    let links_to_download: Vec<String> = content
-        .split_whitespace()
-        .filter(|word| word.starts_with("+http"))
-        .map(|s| s.to_string())
-        .collect();
+#[derive(Serialize)]
+struct HealthResponse {
+    status: &'static str,
+    version: &'static str,
+    note_count: usize,
+    uptime_seconds: u64,
+    /// Number of attachments and their size, up to 30 seconds old
+    attachment_count: usize,
+    attachment_bytes: u64,
+    /// `--yt-dlp-format`
+    yt_dlp_format: String,
+}
 
-    fs::create_dir_all("attachments/webpages").unwrap();
+/// Word statistics of a note, stop words left out.
+#[derive(Serialize)]
+struct NoteAnalytics {
+    word_frequency: BTreeMap<String, usize>,
+    unique_words: usize,
+    /// In characters, over every occurrence of a word
+    avg_word_length: f64,
+}
 
-    for link in &links_to_download {
-        let url = &link[1..];
-        let escaped_filename = url_to_safe_filename(url);
-        let filepath = format!("attachments/webpages/{}.html", escaped_filename);
-        content = content.replace(link, &format!("{} ([local copy](/{}))", url, filepath));
-    }
+#[derive(Serialize)]
+struct LinkCheck {
+    url: String,
+    /// Status of the response after redirects, `null` if there was none
+    status: Option<u16>,
+    reachable: bool,
+}
 
-    let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
-    let html = md_to_html(&content); // Changed to pass a reference
-    let note = Note {
-        timestamp: timestamp.clone(),
-        content: content.clone(),
-        html,
-    };
+#[derive(Serialize)]
+struct Verification {
+    valid: bool,
+    /// Checksum of the note in memory
+    expected: String,
+    /// Checksum of the note in the notes file, `null` if it's missing there
+    /// or the notes file can't be read
+    actual: Option<String>,
+    /// Checksum saved with the note in the notes file, if any
+    stored: Option<String>,
+}
 
-    state.notes.lock().unwrap().push(note);
+#[derive(Serialize)]
+struct DownloadsResponse {
+    #[serde(flatten)]
+    queue: QueueStatus,
+    /// Pending and in-progress downloads
+    downloads: Vec<NoteDownload>,
+}
 
-    let mut file = fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(&state.notes_file)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+#[derive(Serialize)]
+struct NoteDownload {
+    note_id: usize,
+    #[serde(flatten)]
+    download: DownloadStatus,
+}
 
-    write!(file, "{}\n{}\n\n---\n\n", timestamp, content)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+/// Body of `507 Insufficient Storage` responses to uploads.
+#[derive(Serialize)]
+struct QuotaExceeded {
+    error: &'static str,
+    current_files: usize,
+    limit_files: Option<usize>,
+    current_bytes: u64,
+    limit_bytes: Option<u64>,
+}
 
-    info!("Note created: {}", timestamp);
+#[derive(Deserialize)]
+struct RenumberQuery {
+    #[serde(default)]
+    confirm: bool,
+}
 
-    if !links_to_download.is_empty() {
-        let notes = state.notes.clone();
-        spawn(async move {
-            for link in links_to_download {
-                let url = &link[1..];
-                let escaped_filename = url_to_safe_filename(url);
-                let filepath = format!("attachments/webpages/{}.html", escaped_filename);
+/// A note in the body of `POST /import`; notes without a timestamp get the current time.
+#[derive(Deserialize)]
+struct ImportedNote {
+    /// Id of the note where it was exported from
+    id: Option<usize>,
+    timestamp: Option<String>,
+    content: String,
+}
 
-                let result = Command::new("monolith")
-                    .args(&[url, "-o", &filepath])
-                    .output()
-                    .await;
+#[derive(Deserialize)]
+struct ImportQuery {
+    /// Download `+http` links right away instead of leaving them for `POST /notes/:id/download`
+    #[serde(default)]
+    downloads: bool,
+    #[serde(default)]
+    conflict: ImportConflict,
+}
 
-                info!("Downloading webpage: {}", url);
+/// What to do with an imported note whose id is already taken.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ImportConflict {
+    /// Leave the existing note alone and drop the imported one
+    Skip,
+    /// Replace the existing note with the imported one
+    Overwrite,
+    /// Append the imported note with a new id
+    #[default]
+    Reassign,
+}
 
-                if result.is_err() {
-                    error!("Failed to download webpage: {}", url);
-                    let mut notes_lock = notes.lock().unwrap();
-                    if let Some(last_note) = notes_lock.last_mut() {
-                        let updated_content = last_note.content.replace(
-                            &format!("([local copy](/{}))", filepath),
-                            "(local copy failed)",
-                        );
-                        last_note.content = updated_content.clone();
-                        last_note.html = md_to_html(&updated_content); // Changed to pass a reference here too
-
-                        drop(notes_lock);
-
-                        if let Ok(file_content) = fs::read_to_string(&state.notes_file) {
-                            let notes_lock = notes.lock().unwrap();
-                            let updated_content: Vec<String> = file_content
-                                .split("\n---\n")
-                                .enumerate()
-                                .map(|(i, note_content)| {
-                                    if i == notes_lock.len() - 1 {
-                                        format!("{}\n{}", timestamp, updated_content)
-                                    } else {
-                                        note_content.to_string()
-                                    }
-                                })
-                                .collect();
-                            drop(notes_lock);
-
-                            if let Ok(mut file) = fs::File::create(&state.notes_file) {
-                                for note_content in updated_content {
-                                    writeln!(file, "{}\n---", note_content).ok();
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        });
-    }
+#[derive(Default, Serialize)]
+struct ImportResult {
+    /// Notes added or overwritten
+    imported: usize,
+    added: usize,
+    skipped: usize,
+    /// Ids of the overwritten notes
+    overwritten: Vec<usize>,
+    /// Old id of an imported note mapped to its id here, for notes whose id changed
+    remapped: BTreeMap<usize, usize>,
+}
 
-    Ok(())
+#[derive(Default, Serialize)]
+struct FileImportResult {
+    imported: usize,
+    /// Notes that were already here
+    skipped: usize,
+    /// Notes that couldn't be imported and why
+    errors: Vec<String>,
 }
 
-// route POST /upload
-async fn upload_file(mut multipart: Multipart) -> Result<Json<String>, StatusCode> {
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        let name = field.file_name().unwrap().to_string();
-        let data = field.bytes().await.unwrap();
+/// One operation of `POST /batch`, e.g. `{"op": "update", "id": 3, "content": "..."}`.
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum BatchOp {
+    Create { content: String },
+    Update { id: usize, content: String },
+    Delete { id: usize },
+}
 
-        info!("Uploading file: {}", name);
+#[derive(Serialize)]
+struct BatchResult {
+    ok: bool,
+    /// The created or updated note
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<Note>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
 
-        let original_path = PathBuf::from("attachments").join(&name);
-        let mut counter = 1;
-
-        let original_stem = original_path
-            .file_stem()
-            .and_then(|s| s.to_str())
-            .unwrap_or("");
-        let original_ext = original_path
-            .extension()
-            .and_then(|s| s.to_str())
-            .unwrap_or("");
-
-        // Generate unique filename if already exists
-        let mut path = original_path.clone();
-        while path.exists() {
-            // e.g: file-1.txt
-            let new_name = if original_ext.is_empty() {
-                format!("{}-{}", original_stem, counter)
-            } else {
-                format!("{}-{}.{}", original_stem, counter, original_ext)
-            };
+/// The note an attachment was uploaded for. The timestamp identifies the note
+/// even after deletions have shifted its id.
+#[derive(Clone, Serialize, Deserialize)]
+struct AttachmentNote {
+    note_id: usize,
+    note_timestamp: String,
+}
 
-            path = original_path.parent().unwrap().join(new_name);
-            counter += 1;
-        }
+/// Response of `POST /upload` when the upload was for a note.
+#[derive(Serialize)]
+struct AttachmentUpload {
+    url: String,
+    note: AttachmentNote,
+}
 
-        fs::write(&path, data).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+#[derive(Serialize)]
+#[serde(untagged)]
+enum UploadedFile {
+    Url(String),
+    Attached(AttachmentUpload),
+}
 
-        info!("File saved as {}", path.display());
-        return Ok(Json(format!(
-            "/attachments/{}",
-            path.file_name().unwrap().to_str().unwrap()
-        )));
-    }
+#[derive(Serialize)]
+struct UploadFailure {
+    name: String,
+    error: String,
+}
 
-    error!("Error uploading file");
-    Err(StatusCode::BAD_REQUEST)
+/// Response of `POST /upload` when some of the files couldn't be saved.
+#[derive(Serialize)]
+struct UploadResults {
+    succeeded: Vec<UploadedFile>,
+    failed: Vec<UploadFailure>,
 }
 
-// UTILS
-fn md_to_html(markdown: &str) -> String {
-    let mut options = Options::default();
-    options.extension.strikethrough = true;
-    options.extension.tagfilter = true;
-    options.extension.table = true;
-    options.extension.autolink = true;
-    options.extension.tasklist = true;
-    options.extension.superscript = true;
-    options.render.unsafe_ = true;
-    markdown_to_html(markdown, &options)
+#[derive(Serialize)]
+struct RerenderResult {
+    total: usize,
+    changed: usize,
 }
 
-fn url_to_safe_filename(url: &str) -> String {
-    let mut safe_name = String::with_capacity(url.len());
+#[derive(Clone)]
+struct AppState {
+    html: String,
+    notes_html: String,
+    manifest: String,
+    /// App icons by size, generated from the favicon at startup
+    icons: Arc<BTreeMap<u16, String>>,
+    notes: Arc<RwLock<Vec<Note>>>,
+    notes_file: PathBuf,
+    notes_format: NotesFormat,
+    git_commit: bool,
+    id_offset: usize,
+    allowed_hosts: Vec<String>,
+    request_id_header: HeaderName,
+    note_rate_limit: RateLimiter,
+    upload_rate_limit: RateLimiter,
+    behind_proxy: bool,
+    read_only: bool,
+    // expected `Authorization` header value, if a password is required
+    credentials: Option<String>,
+    auto_tags: Vec<AutoTag>,
+    render_config: RenderConfig,
+    upload_field: String,
+    allowed_types: Vec<String>,
+    max_attachments: Option<usize>,
+    max_attachment_storage: Option<u64>,
+    link_mode: LinkMode,
+    refresh_snapshots: bool,
+    download_timeout: Duration,
+    downloads: DownloadQueue,
+    monolith_args: Vec<String>,
+    yt_dlp_format: String,
+    audio_hosts: Vec<String>,
+    download_tracker: DownloadTracker,
+    retry_policy: RetryPolicy,
+    allow_empty_overwrite: bool,
+    max_note_size: usize,
+    timestamp_format: String,
+    timezone: Option<FixedOffset>,
+    search_mode: SearchMode,
+    drafts: Arc<Mutex<Drafts>>,
+    attachments_usage: Arc<Mutex<Option<(Instant, AttachmentsUsage, usize)>>>,
+    started: Instant,
+    backup_dir: Option<PathBuf>,
+    events: broadcast::Sender<NoteEvent>,
+}
 
-    let stripped_url = url
-        .trim()
-        .strip_prefix("http://")
-        .unwrap_or(url)
-        .strip_prefix("https://")
-        .unwrap_or(url);
+/// Bytes used per subdirectory of `attachments`.
+type AttachmentsUsage = BTreeMap<String, u64>;
 
-    for c in stripped_url.chars() {
-        match c {
-            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => safe_name.push('_'),
-            c if c.is_alphanumeric() || c == '-' || c == '.' || c == '_' => safe_name.push(c),
-            _ => safe_name.push('_'),
+impl AppState {
+    /// The state of a server started with `args`, serving `notes` from
+    /// `notes_file`.
+    fn new(
+        args: &Args,
+        notes: Vec<Note>,
+        notes_file: PathBuf,
+        notes_format: NotesFormat,
+        read_only: bool,
+        render_config: RenderConfig,
+    ) -> Self {
+        let favicon = format!(
+            "data:image/svg+xml;base64,{}",
+            Base64Display::new(FAVICON_SVG, &STANDARD)
+        );
+        let fill_template = |template: &str| {
+            template
+                .replace("{{FAVICON}}", &favicon)
+                .replace("{{APP_NAME}}", &escape_html(&args.app_name))
+                .replace("{{THEME_COLOR}}", &escape_html(&args.theme_color))
+        };
+        let html = fill_template(INDEX_HTML);
+        let notes_html = fill_template(NOTES_HTML);
+        let manifest = web_app_manifest(args);
+        let icons = ICON_SIZES
+            .iter()
+            .map(|&size| (size, app_icon(size, &args.theme_color)))
+            .collect();
+
+        AppState {
+            html,
+            notes_html,
+            manifest,
+            icons: Arc::new(icons),
+            notes: Arc::new(RwLock::new(notes)),
+            notes_file,
+            notes_format,
+            git_commit: args.git_commit,
+            id_offset: args.id_offset,
+            allowed_hosts: args.allowed_hosts.clone(),
+            request_id_header: args.request_id_header.clone(),
+            note_rate_limit: RateLimiter::new(args.rate_limit_notes),
+            upload_rate_limit: RateLimiter::new(args.rate_limit_uploads),
+            behind_proxy: args.behind_proxy,
+            read_only,
+            credentials: args.password.as_ref().map(|password| {
+                format!(
+                    "Basic {}",
+                    STANDARD.encode(format!("{}:{password}", args.username))
+                )
+            }),
+            auto_tags: args.auto_tags.clone(),
+            render_config,
+            upload_field: args.upload_field.clone(),
+            allowed_types: args.allowed_types.clone(),
+            max_attachments: args.max_attachments,
+            max_attachment_storage: args.max_attachment_storage,
+            link_mode: args.link_mode,
+            refresh_snapshots: args.refresh_snapshots,
+            download_timeout: Duration::from_secs(args.download_timeout),
+            downloads: DownloadQueue::new(args.download_concurrency),
+            download_tracker: DownloadTracker::default(),
+            yt_dlp_format: args.yt_dlp_format.clone(),
+            audio_hosts: args.audio_hosts.clone(),
+            // already checked by `parse_monolith_args`
+            monolith_args: args
+                .monolith_args
+                .iter()
+                .flat_map(|args| shell_words::split(args).unwrap())
+                .collect(),
+            retry_policy: RetryPolicy {
+                max_attempts: args.download_retries + 1,
+                initial_delay: Duration::from_secs(args.download_retry_delay),
+                backoff_factor: 2.0,
+            },
+            allow_empty_overwrite: args.allow_empty_overwrite,
+            max_note_size: args.max_note_size,
+            timestamp_format: args.timestamp_format.clone(),
+            timezone: args.timezone,
+            search_mode: args.search_mode,
+            drafts: Arc::new(Mutex::new(Drafts::default())),
+            attachments_usage: Arc::new(Mutex::new(None)),
+            started: Instant::now(),
+            backup_dir: args.backup_dir.clone(),
+            events: broadcast::channel(64).0,
         }
     }
 
-    safe_name.trim_matches(|c| c == '.' || c == ' ').to_string()
+    /// Id the next created note gets, i.e. the one right after the last note.
+    fn next_note_id(&self, notes: &[Note]) -> usize {
+        self.id_offset + notes.len()
+    }
+
+    /// Refuses content over `--max-note-size`. This is checked on the parsed
+    /// content, unlike `CONTENT_LENGTH_LIMIT`, which limits whole request
+    /// bodies (uploads included).
+    fn check_note_size(&self, content: &str) -> Result<(), (StatusCode, String)> {
+        if content.len() <= self.max_note_size {
+            return Ok(());
+        }
+        Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "the note is {} bytes, more than the limit of {} bytes",
+                content.len(),
+                self.max_note_size
+            ),
+        ))
+    }
+
+    /// Position in `notes` of the note with the given id.
+    fn note_index(&self, notes: &[Note], id: usize) -> Option<usize> {
+        id.checked_sub(self.id_offset)
+            .filter(|&index| index < notes.len())
+    }
+
+    /// Writes all notes to the notes file.
+    fn save_notes(&self, notes: &[Note]) -> std::io::Result<()> {
+        write_notes_to_file(
+            &self.notes_file,
+            self.notes_format,
+            notes,
+            self.allow_empty_overwrite,
+        )
+    }
+
+    /// Writes the notes left after the user deleted some. Unlike `save_notes`
+    /// this may empty the notes file, since that's what was asked for.
+    fn save_notes_after_delete(&self, notes: &[Note]) -> std::io::Result<()> {
+        write_notes_to_file(&self.notes_file, self.notes_format, notes, true)
+    }
+
+    /// Saves the last `added` of `notes`, which were just added. Markdown
+    /// notes files are appended to, others are written as a whole.
+    fn append_notes(&self, notes: &[Note], added: usize) -> std::io::Result<()> {
+        if self.notes_format != NotesFormat::Markdown {
+            return self.save_notes(notes);
+        }
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.notes_file)?;
+        file.write_all(
+            notes[notes.len() - added..]
+                .iter()
+                .map(note_block)
+                .collect::<String>()
+                .as_bytes(),
+        )
+    }
+
+    /// The current time formatted with `--timestamp-format`.
+    fn now(&self) -> String {
+        format_timestamp(Utc::now(), self.timezone, &self.timestamp_format)
+    }
+
+    /// The current date in the time zone of the timestamps.
+    fn today(&self) -> NaiveDate {
+        match self.timezone {
+            Some(timezone) => Utc::now().with_timezone(&timezone).date_naive(),
+            None => Local::now().date_naive(),
+        }
+    }
+
+    /// Disk usage and number of files of the attachments. Walking them is
+    /// comparatively slow, so a result up to `max_age` old is reused.
+    fn attachment_usage(&self, max_age: Duration) -> (AttachmentsUsage, usize) {
+        let mut usage = self.attachments_usage.lock().unwrap();
+        match usage.as_ref() {
+            Some((computed_at, attachments, files)) if computed_at.elapsed() < max_age => {
+                (attachments.clone(), *files)
+            }
+            _ => {
+                let (attachments, files) = attachments_usage(&PathBuf::from("attachments"));
+                *usage = Some((Instant::now(), attachments.clone(), files));
+                (attachments, files)
+            }
+        }
+    }
+
+    /// Tells `GET /events` subscribers about a change, if there are any.
+    fn notify(&self, event: NoteEventKind, note_id: usize) {
+        let _ = self.events.send(NoteEvent { event, note_id });
+    }
+
+    /// Commits the notes directory to git in the background when `--git-commit` is set.
+    /// Returns the background task, if any.
+    fn commit_changes(&self, message: String) -> Option<JoinHandle<()>> {
+        if !self.git_commit {
+            return None;
+        }
+
+        let dir = match self.notes_file.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+
+        Some(spawn(async move {
+            // one commit at a time, otherwise git trips over its own index.lock
+            let _guard = GIT_LOCK.lock().await;
+
+            for args in [vec!["add", "-A"], vec!["commit", "-q", "-m", &message]] {
+                match Command::new("git")
+                    .args(&args)
+                    .current_dir(&dir)
+                    .output()
+                    .await
+                {
+                    Ok(output) if output.status.success() => {}
+                    Ok(output) => {
+                        warn!(
+                            "git {} failed: {}",
+                            args[0],
+                            String::from_utf8_lossy(&output.stderr).trim()
+                        );
+                        return;
+                    }
+                    Err(e) => {
+                        warn!("could not run git: {e}");
+                        return;
+                    }
+                }
+            }
+
+            info!("Committed changes: {message}");
+        }))
+    }
+}
+
+static GIT_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+const CONTENT_LENGTH_LIMIT: usize = 500 * 1024 * 1024; // allow uploading up to 500mb files... overkill?
+
+// pasted images are screenshots and the like
+const RAW_UPLOAD_LIMIT: usize = 50 * 1024 * 1024;
+const DEFAULT_PAGE_LIMIT: usize = 50;
+const MAX_PAGE_LIMIT: usize = 1000;
+// outside `attachments`, which is served to anyone
+const ATTACHMENT_INDEX: &str = "attachment-index.json";
+// where older versions kept it
+const OLD_ATTACHMENT_INDEX: &str = "attachments/.index.json";
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+// editors often write a file in several steps, e.g. truncate then write
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(200);
+const BACKUPS_KEPT: usize = 30;
+const SSE_RETRY: Duration = Duration::from_secs(5);
+const WORDS_PER_MINUTE: u64 = 200;
+const ATTACHMENTS_USAGE_TTL: Duration = Duration::from_secs(30);
+const LINK_CHECK_CONCURRENCY: usize = 8;
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+const MAX_SLUG_LENGTH: usize = 50;
+const LINK_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+// yt-dlp format selection for audio links, which are converted to MP3 anyway
+const AUDIO_FORMAT: &str = "bestaudio/best";
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    logging::init(args.log_format, args.log_level);
+
+    if StrftimeItems::new(&args.timestamp_format).any(|item| item == Item::Error)
+        || args.timestamp_format.contains('\n')
+    {
+        error!("invalid --timestamp-format {:?}", args.timestamp_format);
+        process::exit(1);
+    }
+
+    let cors = match cors_layer(&args.allow_origins, &args.request_id_header) {
+        Ok(cors) => cors,
+        Err(origin) => {
+            error!("invalid --allow-origin {origin:?}");
+            process::exit(1);
+        }
+    };
+
+    if let Some(path) = &args.base_directory {
+        if let Err(e) = env::set_current_dir(path) {
+            error!("could not change directory to {}: {e}", path.display());
+            process::exit(1);
+        }
+    }
+
+    if let Err(e) = fs::create_dir_all("attachments") {
+        error!(
+            "could not create attachments directory in {}: {e}",
+            env::current_dir().unwrap().display()
+        );
+        process::exit(1);
+    }
+    if !std::path::Path::new(ATTACHMENT_INDEX).exists() {
+        if let Err(e) = fs::rename(OLD_ATTACHMENT_INDEX, ATTACHMENT_INDEX) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                error!("could not move {OLD_ATTACHMENT_INDEX} to {ATTACHMENT_INDEX}: {e}");
+                process::exit(1);
+            }
+        }
+    }
+
+    let render_config = RenderConfig {
+        max_line_length: args.max_line_length,
+        heading_anchors: args.heading_anchors,
+        heading_id_prefix: args.heading_id_prefix.clone(),
+        syntax_theme: Some(args.syntax_theme.clone()).filter(|theme| theme != "none"),
+    };
+    let (notes_file, notes_format) = match &args.obsidian_vault {
+        Some(vault) => (vault.clone(), NotesFormat::Obsidian),
+        None => (
+            args.notes_file
+                .clone()
+                .unwrap_or_else(|| match args.format {
+                    NotesFormat::Markdown => PathBuf::from("notes.md"),
+                    NotesFormat::Json => PathBuf::from("notes.json"),
+                    NotesFormat::Dir | NotesFormat::Obsidian => PathBuf::from("notes"),
+                }),
+            args.format,
+        ),
+    };
+    // an Obsidian vault is never written to
+    let read_only = args.read_only || notes_format == NotesFormat::Obsidian;
+    let notes = load_notes(
+        &notes_file,
+        notes_format,
+        args.id_offset,
+        &render_config,
+        &args.timestamp_format,
+        args.timezone,
+    );
+
+    let current_dir = env::current_dir().unwrap_or_default();
+    info!(
+        "Notes: {} ({} notes loaded)",
+        current_dir.join(&notes_file).display(),
+        notes.len()
+    );
+    info!("Attachments: {}", current_dir.join("attachments").display());
+    info!(
+        "Links: {} mode (monolith {}, curl {}, yt-dlp {})",
+        args.link_mode.to_possible_value().unwrap().get_name(),
+        tool_status("monolith").await,
+        tool_status("curl").await,
+        tool_status("yt-dlp").await,
+    );
+    if args.git_commit {
+        info!(
+            "Committing changes to git (git {})",
+            tool_status("git").await
+        );
+    }
+    if !args.allowed_hosts.is_empty() {
+        info!("Allowed hosts: {}", args.allowed_hosts.join(", "));
+    }
+    if args.password.is_some() {
+        info!("Password protection enabled for user {}", args.username);
+    }
+    if read_only {
+        warn!("Read-only mode: notes and attachments can't be changed");
+    }
+
+    let state = AppState::new(
+        &args,
+        notes,
+        notes_file,
+        notes_format,
+        read_only,
+        render_config,
+    );
+
+    if let Some(Action::Import { file }) = &args.action {
+        if read_only {
+            error!("can't import notes in read-only mode");
+            process::exit(1);
+        }
+        import_file(&state, file).await;
+        return;
+    }
+
+    if let Some(dir) = &args.backup_dir {
+        info!(
+            "Backing up notes to {} every {} hours",
+            dir.display(),
+            args.backup_interval
+        );
+        spawn_backups(
+            state.clone(),
+            Duration::from_secs(args.backup_interval * 60 * 60),
+        );
+    }
+
+    if args.watch {
+        info!("Watching {} for changes", state.notes_file.display());
+        watch_notes_file(state.clone());
+    }
+
+    let app = Router::new()
+        .route("/", get(index))
+        .route("/health", get(get_health))
+        .route("/ready", get(get_ready))
+        .route("/manifest.webmanifest", get(get_manifest))
+        .route("/icons/:file", get(get_icon))
+        .route("/sw.js", get(get_service_worker))
+        .route("/notes", get(get_notes).post(save_note))
+        .route("/notes.html", get(get_notes_html))
+        .route("/notes/stats", get(get_stats))
+        .route("/notes/timeline", get(get_timeline))
+        .route("/notes/search", get(search_notes))
+        .route("/notes/export", get(export_notes))
+        .route("/notes/by-slug/:slug", get(get_note_by_slug))
+        .route("/notes/random", get(get_random_note))
+        .route("/notes/today", get(get_notes_today))
+        .route("/notes/week", get(get_notes_week))
+        .route("/notes/month", get(get_notes_month))
+        .route("/notes/calendar", get(get_notes_calendar))
+        .route(
+            "/notes/duplicates",
+            get(get_duplicates).delete(delete_duplicates),
+        )
+        .route("/notes/raw", get(get_raw_notes))
+        .route("/notes/batch", post(get_notes_by_ids))
+        .route(
+            "/notes/:id",
+            get(get_note_by_id)
+                .put(update_note)
+                .patch(patch_note)
+                .delete(delete_note_by_id),
+        )
+        .route("/notes/:id/content", put(update_note_content))
+        .route("/notes/:id/raw", get(get_raw_note))
+        .route("/notes/:id/slug", put(set_note_slug))
+        .route("/notes/:id/duplicate", post(duplicate_note))
+        .route("/notes/:id/block", get(get_note_block))
+        .route("/notes/:id/verify", get(verify_note))
+        .route("/notes/:id/links", get(get_note_links))
+        .route("/notes/:id/analytics", get(get_note_analytics))
+        .route("/notes/:id/links/check", get(check_note_links))
+        .route("/notes/:id/pin", post(pin_note).delete(unpin_note))
+        .route("/notes/:id/archive", put(archive_note))
+        .route("/notes/:id/unarchive", put(unarchive_note))
+        .route("/notes/:id/download", post(download_note_links))
+        .route("/drafts", get(get_drafts).post(create_draft))
+        .route("/drafts/:id", put(update_draft).delete(delete_draft))
+        .route("/drafts/:id/publish", post(publish_draft))
+        .route("/events", get(get_events))
+        .route("/tags", get(get_tags))
+        .route("/backups", get(get_backups))
+        .route("/api/attachments", get(get_attachments))
+        .route("/api/attachments/:filename", delete(delete_attachment))
+        .route("/downloads", get(get_downloads))
+        .route("/downloads/:id", get(get_note_downloads))
+        .route("/import", post(import_notes))
+        .route("/notes/import", post(import_notes_file))
+        .route("/batch", post(apply_batch))
+        .route("/admin/rerender", post(rerender_notes))
+        .route("/admin/renumber", post(renumber_notes))
+        .layer(TimeoutLayer::new(Duration::from_secs(args.request_timeout)))
+        // uploads of big files on slow connections legitimately take long,
+        // so they (and the attachments below) aren't subject to the timeout
+        .route("/upload", post(upload_file))
+        .layer(DefaultBodyLimit::max(CONTENT_LENGTH_LIMIT))
+        .route(
+            "/upload/raw",
+            post(upload_raw).layer(DefaultBodyLimit::max(RAW_UPLOAD_LIMIT)),
+        )
+        .nest_service("/attachments", ServeDir::new("attachments"))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            check_rate_limit,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            check_read_only,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            check_credentials,
+        ))
+        .layer(middleware::from_fn_with_state(state.clone(), check_host))
+        .layer(middleware::from_fn_with_state(state.clone(), trace_request))
+        .with_state(state);
+    // outermost, so that preflight requests are answered without credentials
+    let app = match cors {
+        Some(cors) => app.layer(cors),
+        None => app,
+    };
+
+    let server_details = format!("{}:{}", args.listen, args.port);
+    let addr: SocketAddr = server_details
+        .parse()
+        .expect("Unable to parse socket address");
+    info!("Starting server on http://{}", addr);
+
+    match tokio::net::TcpListener::bind(&addr).await {
+        Ok(listener) => {
+            // the peer address is needed for rate limits
+            let app = app.into_make_service_with_connect_info::<SocketAddr>();
+            if let Err(e) = axum::serve(listener, app).await {
+                error!("Server error: {}", e);
+            }
+        }
+        Err(e) => {
+            error!("Failed to bind to address {}: {}", addr, e);
+        }
+    }
+}
+
+// Metadata lines sit between the timestamp and the content of a note block:
+//
+//   2024-01-01 12:00:00
+//   priority: 2
+//   classes: pinned wide
+//   tags: link todo
+//   pinned: true
+//   archived: true
+//   preview: {"url": "https://example.com", "title": "Example", ...}
+//   Content of the note
+//
+// Returns the content following the metadata lines. A first line of content
+// that could be taken for metadata is saved with a `\` in front, see
+// `needs_escape`, which is removed again here.
+fn parse_metadata<'a>(note: &mut Note, mut content: &'a str, timestamp_format: &str) -> &'a str {
+    loop {
+        let (line, rest) = content.split_once('\n').unwrap_or((content, ""));
+        match line.split_once(": ") {
+            Some(("priority", value)) if value.trim().parse::<i32>().is_ok() => {
+                note.priority = value.trim().parse().ok();
+            }
+            Some(("classes", value)) if value.split_whitespace().all(valid_class_name) => {
+                note.classes = value.split_whitespace().map(String::from).collect();
+            }
+            Some(("modified", value)) if parse_timestamp(value, timestamp_format).is_some() => {
+                note.modified = Some(value.to_string());
+            }
+            Some(("slug", value)) if valid_slug(value) => note.slug = Some(value.to_string()),
+            Some(("checksum", value))
+                if value.len() == 8 && value.chars().all(|c| c.is_ascii_hexdigit()) =>
+            {
+                note.checksum = Some(value.to_string());
+            }
+            Some(("pinned", "true")) => note.pinned = true,
+            Some(("archived", "true")) => note.archived = true,
+            Some(("tags", value)) if value.split_whitespace().all(valid_tag) => {
+                note.tags = value.split_whitespace().map(String::from).collect();
+            }
+            Some(("preview", value)) if serde_json::from_str::<LinkPreview>(value).is_ok() => {
+                note.previews.extend(serde_json::from_str(value).ok());
+            }
+            _ => break,
+        }
+        content = rest;
+    }
+
+    match content.strip_prefix('\\') {
+        Some(unescaped) if needs_escape(unescaped) => unescaped,
+        _ => content,
+    }
+}
+
+// Keys of the metadata lines, see `parse_metadata`.
+const METADATA_KEYS: &[&str] = &[
+    "modified", "priority", "classes", "tags", "slug", "pinned", "archived", "checksum", "preview",
+];
+
+// Whether content starting like `content` has to be escaped with a `\` when
+// saved, so its first line isn't read as metadata. Content starting with a
+// `\` is escaped too, to keep that `\` when it's read back.
+fn needs_escape(content: &str) -> bool {
+    let first_line = content.split('\n').next().unwrap_or_default();
+    first_line.starts_with('\\')
+        || first_line
+            .split_once(": ")
+            .is_some_and(|(key, _)| METADATA_KEYS.contains(&key))
+}
+
+// Class names end up in HTML attributes, so only plain names are allowed.
+fn valid_class_name(class: &str) -> bool {
+    !class.is_empty()
+        && class
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn valid_tag(tag: &str) -> bool {
+    !tag.is_empty()
+        && tag
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+}
+
+fn valid_slug(slug: &str) -> bool {
+    !slug.is_empty()
+        && slug.chars().count() <= MAX_SLUG_LENGTH
+        && slug
+            .chars()
+            .all(|c| c.is_alphanumeric() && !c.is_uppercase() || c == '-')
+}
+
+// The first line of `content` in lowercase, with everything but letters and
+// digits turned into single hyphens, or `note` if that leaves nothing.
+fn slugify(content: &str) -> String {
+    let first_line = content.lines().next().unwrap_or_default().to_lowercase();
+    let slug = first_line
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+    let slug = slug.chars().take(MAX_SLUG_LENGTH).collect::<String>();
+    match slug.trim_end_matches('-') {
+        "" => "note".to_string(),
+        slug => slug.to_string(),
+    }
+}
+
+// `slugify(content)`, with `-2`, `-3`, ... added if other notes have it.
+fn unique_slug(notes: &[Note], content: &str) -> String {
+    let base = slugify(content);
+    let taken = |slug: &str| notes.iter().any(|note| note.slug.as_deref() == Some(slug));
+    if !taken(&base) {
+        return base;
+    }
+    (2..)
+        .map(|n| {
+            let suffix = format!("-{n}");
+            let kept = MAX_SLUG_LENGTH - suffix.len();
+            format!("{}{suffix}", base.chars().take(kept).collect::<String>())
+        })
+        .find(|slug| !taken(slug))
+        .unwrap()
+}
+
+// Gives the notes that have no slug yet (e.g. saved by an older version) one,
+// and a new one to those whose slug is invalid or taken by an earlier note
+// (e.g. imported, or edited by hand).
+fn assign_slugs(notes: &mut [Note]) {
+    for index in 0..notes.len() {
+        let (earlier, rest) = notes.split_at(index);
+        let usable = rest[0].slug.as_deref().is_some_and(|slug| {
+            valid_slug(slug)
+                && !earlier
+                    .iter()
+                    .any(|note| note.slug.as_deref() == Some(slug))
+        });
+        if !usable {
+            notes[index].slug = None;
+            let slug = unique_slug(notes, &notes[index].content);
+            notes[index].slug = Some(slug);
+        }
+    }
+}
+
+/// Serializes a note the way it's stored in the notes file.
+fn note_block(note: &Note) -> String {
+    let mut block = format!("{}\n", note.timestamp);
+    if let Some(modified) = &note.modified {
+        block.push_str(&format!("modified: {modified}\n"));
+    }
+    if let Some(priority) = note.priority {
+        block.push_str(&format!("priority: {priority}\n"));
+    }
+    if !note.classes.is_empty() {
+        block.push_str(&format!("classes: {}\n", note.classes.join(" ")));
+    }
+    if !note.tags.is_empty() {
+        block.push_str(&format!("tags: {}\n", note.tags.join(" ")));
+    }
+    if let Some(slug) = &note.slug {
+        block.push_str(&format!("slug: {slug}\n"));
+    }
+    if note.pinned {
+        block.push_str("pinned: true\n");
+    }
+    if note.archived {
+        block.push_str("archived: true\n");
+    }
+    block.push_str(&format!("checksum: {}\n", content_checksum(&note.content)));
+    for preview in &note.previews {
+        // serde_json escapes newlines, so a preview always fits on one line
+        block.push_str(&format!(
+            "preview: {}\n",
+            serde_json::to_string(preview).unwrap()
+        ));
+    }
+    if needs_escape(&note.content) {
+        block.push('\\');
+    }
+    block.push_str(&format!("{}\n\n---\n\n", note.content));
+    block
+}
+
+fn web_app_manifest(args: &Args) -> String {
+    let icons = ICON_SIZES
+        .iter()
+        .map(|size| {
+            serde_json::json!({
+                "src": format!("/icons/{size}.svg"),
+                "sizes": format!("{size}x{size}"),
+                "type": "image/svg+xml",
+            })
+        })
+        .collect::<Vec<_>>();
+
+    serde_json::json!({
+        "name": args.app_name,
+        "short_name": args.app_name,
+        "start_url": "/",
+        "display": "standalone",
+        "theme_color": args.theme_color,
+        "background_color": args.background_color,
+        "icons": icons,
+    })
+    .to_string()
+}
+
+// The favicon is a black outline on a transparent background, which is
+// invisible on a dark home screen, so the icon gets a colored background.
+fn app_icon(size: u16, background: &str) -> String {
+    let favicon = String::from_utf8_lossy(FAVICON_SVG);
+    let (svg_tag, rest) = favicon.split_once('>').unwrap();
+    let svg_tag = svg_tag
+        .replace(
+            r#"width="24" height="24""#,
+            &format!(r#"width="{size}" height="{size}""#),
+        )
+        .replace(r#"fill="currentColor""#, r#"fill="black""#);
+
+    format!(
+        r#"{svg_tag}><rect width="24" height="24" fill="{}"/><g transform="translate(3 3) scale(0.75)">{}"#,
+        escape_html(background),
+        rest.replace("</svg>", "</g></svg>")
+    )
+}
+
+// Whether an external tool we shell out to is installed, for the startup log.
+async fn tool_status(tool: &str) -> &'static str {
+    match Command::new(tool).arg("--version").output().await {
+        Ok(_) => "found",
+        Err(_) => "not found",
+    }
+}
+
+// Reads the notes at startup (or when the notes file changed) and renders
+// them. Exits if a JSON notes file can't be read.
+fn load_notes(
+    file: &PathBuf,
+    format: NotesFormat,
+    id_offset: usize,
+    render_config: &RenderConfig,
+    timestamp_format: &str,
+    timezone: Option<FixedOffset>,
+) -> Vec<Note> {
+    let mut notes = match read_notes_file(file, format, id_offset, timestamp_format, timezone) {
+        Ok(notes) => notes,
+        Err(e) => {
+            error!("could not read notes from {}: {e}", file.display());
+            process::exit(1);
+        }
+    };
+    for note in &mut notes {
+        note.render(render_config);
+    }
+    assign_slugs(&mut notes);
+    notes
+}
+
+// The notes as saved, with ids but without HTML, and with the checksums saved
+// in the notes file. A missing notes file has no notes; one that can't be
+// parsed is an error.
+//
+// A block whose first line isn't a timestamp in `timestamp_format` (e.g. in a
+// file written by another app) is taken as content as a whole. Such notes get
+// the modification time of the file as timestamp.
+fn read_notes_file(
+    file: &PathBuf,
+    format: NotesFormat,
+    id_offset: usize,
+    timestamp_format: &str,
+    timezone: Option<FixedOffset>,
+) -> std::io::Result<Vec<Note>> {
+    let mut notes = match format {
+        NotesFormat::Obsidian => obsidian::load(file, timestamp_format, timezone),
+        NotesFormat::Dir => notes_dir::load(file, timestamp_format, timezone),
+        NotesFormat::Json | NotesFormat::Markdown => {
+            let Ok(content) = fs::read_to_string(file) else {
+                return Ok(Vec::new());
+            };
+            if format == NotesFormat::Json {
+                parse_json_notes(&content)?
+            } else {
+                let modified = fs::metadata(file)
+                    .and_then(|metadata| metadata.modified())
+                    .map(DateTime::<Utc>::from)
+                    .unwrap_or_else(|_| Utc::now());
+                let modified = format_timestamp(modified, timezone, timestamp_format);
+                parse_note_blocks(&content, timestamp_format, &modified)
+            }
+        }
+    };
+    for (index, note) in notes.iter_mut().enumerate() {
+        note.id = id_offset + index;
+    }
+    Ok(notes)
+}
+
+// Reads notes in the format of `note_block`. Ids are the positions of the
+// notes and their HTML is left empty.
+fn parse_note_blocks(content: &str, timestamp_format: &str, fallback_timestamp: &str) -> Vec<Note> {
+    content
+        .split("\n\n---\n\n")
+        .filter(|s| !s.trim().is_empty())
+        .enumerate()
+        .map(|(index, block)| {
+            let (first_line, rest) = block.split_once('\n').unwrap_or((block, ""));
+            let (timestamp, content) = match parse_timestamp(first_line.trim(), timestamp_format) {
+                Some(_) => (first_line.trim().to_string(), rest.trim()),
+                None => {
+                    warn!(
+                        "Note #{} has no timestamp, using {}",
+                        index, fallback_timestamp
+                    );
+                    (fallback_timestamp.to_string(), block.trim())
+                }
+            };
+
+            let mut note = Note::new(index, timestamp, String::new());
+            note.content = parse_metadata(&mut note, content, timestamp_format)
+                .trim()
+                .to_string();
+            note.check_checksum();
+            note
+        })
+        .collect()
+}
+
+// Ids are positions, so the ids saved in the file are ignored.
+fn parse_json_notes(content: &str) -> std::io::Result<Vec<Note>> {
+    if content.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let notes: Vec<Note> = serde_json::from_str(content)?;
+    for note in &notes {
+        note.check_checksum();
+    }
+    Ok(notes)
+}
+
+// A `key` no other note has had since textpod started.
+fn next_note_key() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    COUNTER.fetch_add(1, Ordering::Relaxed)
+}
+
+/// CRC-32 of the content as 8 hex digits, to notice notes damaged on disk.
+/// Surrounding whitespace is ignored since it isn't kept in the notes file.
+fn content_checksum(content: &str) -> String {
+    format!("{:08x}", crc32fast::hash(content.trim().as_bytes()))
+}
+
+// Also accepts formats without a time of day, such as `%Y-%m-%d`.
+fn parse_timestamp(timestamp: &str, format: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(timestamp, format)
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(timestamp, format)
+                .ok()
+                .map(|date| date.and_time(NaiveTime::MIN))
+        })
+}
+
+// Gives every request an id, the one in `--request-id-header` if the client
+// (or a proxy) sent one, logs everything done for the request in a span with
+// that id and returns the id in the same header.
+async fn trace_request(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let header = &state.request_id_header;
+    let id = request
+        .headers()
+        .get(header)
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH && id.to_str().is_ok())
+        .cloned()
+        .unwrap_or_else(|| HeaderValue::from_str(&new_request_id()).unwrap());
+    request.headers_mut().insert(header.clone(), id.clone());
+
+    let span = info_span!(
+        "request",
+        request_id = %id.to_str().unwrap_or_default(),
+        method = %request.method(),
+        path = %request.uri().path(),
+    );
+    let mut response = next.run(request).instrument(span).await;
+    response.headers_mut().insert(header.clone(), id);
+    response
+}
+
+// A random UUID (version 4), also used to name pasted images. Without a random number crate, the randomness
+// comes from the hasher keys std picks at random for every `RandomState`;
+// the counter makes every id of the process different.
+fn new_request_id() -> String {
+    static KEYS: LazyLock<RandomState> = LazyLock::new(RandomState::new);
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let bits = (KEYS.hash_one((n, 0)) as u128) << 64 | KEYS.hash_one((n, 1)) as u128;
+    // version 4, variant 10
+    let bits = bits & !(0xf << 76) | 0x4 << 76;
+    let bits = bits & !(0x3 << 62) | 0x2 << 62;
+    let hex = format!("{bits:032x}");
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+// A random number below `n` (which must not be 0), from the same random keys
+// as the request ids, since there is no random number crate.
+fn random_below(n: usize) -> usize {
+    static KEYS: LazyLock<RandomState> = LazyLock::new(RandomState::new);
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let bits = KEYS.hash_one(COUNTER.fetch_add(1, Ordering::Relaxed));
+    (bits % n as u64) as usize
+}
+
+// Rejects requests whose Host isn't one of `--allowed-host`, which protects
+// a local instance against DNS rebinding. Without the flag every host is fine.
+async fn check_host(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, (StatusCode, String)> {
+    if state.allowed_hosts.is_empty() {
+        return Ok(next.run(request).await);
+    }
+
+    let host = request
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| {
+            request
+                .uri()
+                .authority()
+                .map(|authority| authority.as_str())
+        })
+        .unwrap_or_default();
+    // an allowed host without a port allows it on any port
+    let hostname = host
+        .rsplit_once(':')
+        .filter(|(_, port)| port.chars().all(|c| c.is_ascii_digit()))
+        .map_or(host, |(hostname, _)| hostname);
+
+    if state
+        .allowed_hosts
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(host) || allowed.eq_ignore_ascii_case(hostname))
+    {
+        Ok(next.run(request).await)
+    } else {
+        warn!("Rejected request for host {:?}", host);
+        Err((
+            StatusCode::BAD_REQUEST,
+            format!("host {host:?} is not allowed"),
+        ))
+    }
+}
+
+// CORS for the `--allow-origin` origins, if any. Returns the first origin
+// that isn't a valid header value.
+fn cors_layer<'a>(
+    origins: &'a [String],
+    request_id_header: &HeaderName,
+) -> Result<Option<CorsLayer>, &'a str> {
+    if origins.is_empty() {
+        return Ok(None);
+    }
+
+    let allow_origin = if origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        let origins = origins
+            .iter()
+            .map(|origin| origin.parse().map_err(|_| origin.as_str()))
+            .collect::<Result<Vec<_>, _>>()?;
+        AllowOrigin::list(origins)
+    };
+
+    Ok(Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods([
+                Method::GET,
+                Method::POST,
+                Method::PUT,
+                Method::DELETE,
+                Method::PATCH,
+            ])
+            .allow_headers([
+                header::CONTENT_TYPE,
+                header::AUTHORIZATION,
+                request_id_header.clone(),
+            ])
+            .expose_headers([request_id_header.clone()]),
+    ))
+}
+
+// With `--password`, every request needs the configured Basic Auth credentials.
+async fn check_credentials(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(credentials) = &state.credentials else {
+        return next.run(request).await;
+    };
+    // for container orchestrators and uptime monitors
+    if matches!(request.uri().path(), "/health" | "/ready") {
+        return next.run(request).await;
+    }
+
+    let given = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .map(|value| value.as_bytes())
+        .unwrap_or_default();
+    if constant_time_eq(given, credentials.as_bytes()) {
+        return next.run(request).await;
+    }
+
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Basic realm=\"textpod\"")],
+        "authentication required",
+    )
+        .into_response()
+}
+
+// Compares without returning early so the time taken doesn't reveal how much
+// of the password was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// In read-only mode, every request that could change something is rejected
+// before it reaches its handler.
+async fn check_read_only(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if !state.read_only || matches!(*request.method(), Method::GET | Method::HEAD) {
+        return next.run(request).await;
+    }
+    // only a POST because the list of ids doesn't fit in a query string
+    if request.uri().path() == "/notes/batch" {
+        return next.run(request).await;
+    }
+
+    (
+        StatusCode::METHOD_NOT_ALLOWED,
+        [(header::ALLOW, "GET, HEAD")],
+        "textpod is running in read-only mode",
+    )
+        .into_response()
+}
+
+// Answers 429 Too Many Requests to clients creating notes or uploading files
+// more often than `--rate-limit-notes` or `--rate-limit-uploads` allow. Every
+// note created by a `POST /batch` counts.
+async fn check_rate_limit(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let (limiter, request, requests) = match (request.method(), request.uri().path()) {
+        (&Method::POST, "/notes") => (&state.note_rate_limit, request, 1),
+        (&Method::POST, "/upload" | "/upload/raw") => (&state.upload_rate_limit, request, 1),
+        (&Method::POST, "/batch") => match batch_creates(request).await {
+            Ok((request, creates)) => (&state.note_rate_limit, request, creates),
+            Err(response) => return response,
+        },
+        _ => return next.run(request).await,
+    };
+    let Some(client) = client_address(&state, &request) else {
+        return next.run(request).await;
+    };
+
+    match limiter.check(client, requests) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => {
+            let seconds = wait.as_secs_f64().ceil() as u64;
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, seconds.to_string())],
+                format!("too many requests, try again in {seconds} seconds"),
+            )
+                .into_response()
+        }
+    }
+}
+
+// How many notes the `POST /batch` `request` creates. Its body has to be read
+// for that, so the request is returned with the body put back. Bodies that
+// aren't a batch create nothing here and are rejected by `apply_batch`.
+async fn batch_creates(request: Request) -> Result<(Request, usize), Response> {
+    let (parts, body) = request.into_parts();
+    let body = axum::body::to_bytes(body, CONTENT_LENGTH_LIMIT)
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()).into_response())?;
+    let creates = serde_json::from_slice::<Vec<BatchOp>>(&body).map_or(0, |ops| {
+        ops.iter()
+            .filter(|op| matches!(op, BatchOp::Create { .. }))
+            .count()
+    });
+    Ok((Request::from_parts(parts, Body::from(body)), creates))
+}
+
+// With `--behind-proxy`, the last address in X-Forwarded-For is the one the
+// proxy received the request from; earlier ones could be made up by the
+// client. Otherwise it's the address of the connection.
+fn client_address(state: &AppState, request: &Request) -> Option<IpAddr> {
+    let forwarded = request
+        .headers()
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .and_then(|address| address.trim().parse().ok());
+    match forwarded {
+        Some(address) if state.behind_proxy => Some(address),
+        _ => request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(address)| address.ip()),
+    }
+}
+
+// route / (root)
+async fn index(State(state): State<AppState>) -> Html<String> {
+    Html(state.html)
+}
+
+// GET /health
+// Cheap enough to be polled frequently.
+async fn get_health(State(state): State<AppState>) -> Json<HealthResponse> {
+    let (attachments, attachment_count) = state.attachment_usage(ATTACHMENTS_USAGE_TTL);
+    Json(HealthResponse {
+        status: "ok",
+        version: env!("CARGO_PKG_VERSION"),
+        note_count: state.notes.read().await.len(),
+        uptime_seconds: state.started.elapsed().as_secs(),
+        attachment_count,
+        attachment_bytes: attachments.values().sum(),
+        yt_dlp_format: state.yt_dlp_format.clone(),
+    })
+}
+
+// GET /ready
+// Like `/health`, but 503 Service Unavailable if the notes are held by a
+// write that doesn't finish within a second or the drafts are unusable.
+async fn get_ready(
+    State(state): State<AppState>,
+) -> Result<Json<HealthResponse>, (StatusCode, String)> {
+    let note_count = timeout(Duration::from_secs(1), state.notes.read())
+        .await
+        .map_err(|_| {
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "notes are locked".to_string(),
+            )
+        })?
+        .len();
+    if state.drafts.is_poisoned() {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "drafts are unavailable".to_string(),
+        ));
+    }
+
+    let (attachments, attachment_count) = state.attachment_usage(ATTACHMENTS_USAGE_TTL);
+    Ok(Json(HealthResponse {
+        status: "ok",
+        version: env!("CARGO_PKG_VERSION"),
+        note_count,
+        uptime_seconds: state.started.elapsed().as_secs(),
+        attachment_count,
+        attachment_bytes: attachments.values().sum(),
+        yt_dlp_format: state.yt_dlp_format.clone(),
+    }))
+}
+
+// GET /manifest.webmanifest
+async fn get_manifest(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "application/manifest+json")],
+        state.manifest,
+    )
+}
+
+// GET /icons/:file, e.g. /icons/192.svg
+async fn get_icon(
+    State(state): State<AppState>,
+    Path(file): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let size = file
+        .strip_suffix(".svg")
+        .and_then(|size| size.parse::<u16>().ok())
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let icon = state.icons.get(&size).ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(([(header::CONTENT_TYPE, "image/svg+xml")], icon.clone()))
+}
+
+// GET /sw.js
+async fn get_service_worker() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/javascript")],
+        SERVICE_WORKER_JS,
+    )
+}
+
+// GET /notes
+// Without query parameters all notes are returned. With `after` and/or `limit`
+// a page of notes is returned instead, see `NotesPage`. Unless `sort` says
+// otherwise notes are in ascending id order; clients rely on that, so anything
+// producing the notes list has to keep it.
+async fn get_notes(
+    State(state): State<AppState>,
+    Query(query): Query<NotesQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    let mut notes = state.notes.read().await.clone();
+    if !query.include_archived {
+        notes.retain(|note| !note.archived);
+    }
+    if let Some(tag) = &query.tag {
+        notes.retain(|note| note.tags.contains(tag));
+    }
+    if query.from.is_some() || query.to.is_some() {
+        let from = parse_date_bound(query.from.as_deref(), false)?.unwrap_or(NaiveDateTime::MIN);
+        let to = parse_date_bound(query.to.as_deref(), true)?.unwrap_or(NaiveDateTime::MAX);
+        if from > to {
+            return Err((StatusCode::BAD_REQUEST, "from is after to".to_string()));
+        }
+        notes.retain(|note| {
+            parse_timestamp(&note.timestamp, &state.timestamp_format)
+                .is_some_and(|created| from <= created && created <= to)
+        });
+    }
+    match query.sort {
+        None => {
+            // stable, so pinned notes and the others each stay oldest first
+            notes.sort_by_key(|note| !note.pinned);
+        }
+        Some(NotesSort::CreatedAsc) => {}
+        Some(NotesSort::CreatedDesc) => notes.reverse(),
+        Some(NotesSort::ModifiedDesc) => {
+            notes.reverse();
+            // stable, so notes edited at the same time stay newest first
+            notes.sort_by_cached_key(|note| {
+                let modified = note.modified.as_ref().unwrap_or(&note.timestamp);
+                Reverse(parse_timestamp(modified, &state.timestamp_format))
+            });
+        }
+        Some(NotesSort::Priority) => {
+            // stable, so notes of equal priority stay in chronological order
+            notes.sort_by_key(|note| match note.priority {
+                Some(priority) => (0, -(priority as i64)),
+                None => (1, 0),
+            });
+        }
+        Some(NotesSort::Pinned) => {
+            notes.sort_by_key(|note| match note.pinned {
+                true => (0, note.id as i64),
+                false => (1, -(note.id as i64)),
+            });
+        }
+    }
+
+    if query.after.is_none() && query.offset.is_none() && query.limit.is_none() {
+        return Ok(Json(notes).into_response());
+    }
+
+    Ok(Json(notes_page(&notes, &query)?).into_response())
+}
+
+// Reads the `from` or `to` of `GET /notes`. A date without a time stands for
+// the start of the day, or its end if `end_of_day` is set, so ranges of dates
+// include both days.
+fn parse_date_bound(
+    value: Option<&str>,
+    end_of_day: bool,
+) -> Result<Option<NaiveDateTime>, (StatusCode, String)> {
+    let Some(value) = value else {
+        return Ok(None);
+    };
+    if let Ok(time) = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S") {
+        return Ok(Some(time));
+    }
+    match NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        Ok(date) if end_of_day => Ok(Some(date.and_hms_opt(23, 59, 59).unwrap())),
+        Ok(date) => Ok(Some(date.and_time(NaiveTime::MIN))),
+        Err(_) => Err((
+            StatusCode::BAD_REQUEST,
+            format!("invalid date {value:?}, expected e.g. 2024-01-31 or 2024-01-31T12:00:00"),
+        )),
+    }
+}
+
+// Cuts the page of notes `query` asks for out of `notes`.
+fn notes_page(notes: &[Note], query: &NotesQuery) -> Result<NotesPage, (StatusCode, String)> {
+    let start = match (&query.after, query.offset) {
+        (Some(_), Some(_)) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "use either after or offset, not both".to_string(),
+            ))
+        }
+        (Some(cursor), None) => cursor_position(notes, cursor).ok_or((
+            StatusCode::BAD_REQUEST,
+            format!("invalid cursor {cursor:?}"),
+        ))?,
+        (None, Some(offset)) => offset.min(notes.len()),
+        (None, None) => 0,
+    };
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_LIMIT)
+        .min(MAX_PAGE_LIMIT);
+
+    let page = notes
+        .iter()
+        .skip(start)
+        .take(limit)
+        .cloned()
+        .collect::<Vec<_>>();
+    let next_cursor = match page.last() {
+        Some(last) if start + page.len() < notes.len() => Some(note_cursor(last)),
+        _ => None,
+    };
+
+    Ok(NotesPage {
+        notes: page,
+        next_cursor,
+        total: notes.len(),
+        offset: start,
+        limit,
+    })
+}
+
+// GET /notes.html
+// Server-rendered pages of notes for browsers without JavaScript. Takes the
+// same `after` and `limit` parameters as `GET /notes`.
+async fn get_notes_html(
+    State(state): State<AppState>,
+    Query(query): Query<NotesQuery>,
+) -> Result<Html<String>, (StatusCode, String)> {
+    let mut notes = state.notes.read().await.clone();
+    if !query.include_archived {
+        notes.retain(|note| !note.archived);
+    }
+    // pinned notes first, as in `GET /notes`
+    notes.sort_by_key(|note| !note.pinned);
+    let page = notes_page(&notes, &query)?;
+    let start = page.offset;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_LIMIT)
+        .clamp(1, MAX_PAGE_LIMIT);
+
+    let notes_html = page
+        .notes
+        .iter()
+        .map(|note| {
+            format!(
+                "<div class=\"note {}\">{}<div class=\"noteMetadata\">#{} <time datetime=\"{}\">{}</time></div></div>\n",
+                note.classes.join(" "),
+                note.html,
+                note.id,
+                escape_html(&note.timestamp),
+                escape_html(&note.timestamp)
+            )
+        })
+        .collect::<String>();
+
+    let limit_param = query
+        .limit
+        .map(|l| format!("&amp;limit={l}"))
+        .unwrap_or_default();
+    let prev = match start {
+        0 => String::new(),
+        // the previous page ends right before this one
+        _ if start <= limit => format!(
+            "<a href=\"/notes.html?{}\">← previous</a>",
+            limit_param.trim_start_matches("&amp;")
+        ),
+        _ => format!(
+            "<a href=\"/notes.html?after={}{limit_param}\">← previous</a>",
+            note_cursor(&notes[start - limit - 1])
+        ),
+    };
+    let next = match &page.next_cursor {
+        Some(cursor) => format!("<a href=\"/notes.html?after={cursor}{limit_param}\">next →</a>"),
+        None => String::new(),
+    };
+
+    Ok(Html(
+        state
+            .notes_html
+            .replace(
+                "{{PAGINATION}}",
+                &format!("<span>{prev}</span><span>{next}</span>"),
+            )
+            .replace("{{NOTES}}", &notes_html),
+    ))
+}
+
+// GET /notes/search?q=...
+// Notes whose content contains `q`, ignoring case, or matches it as a regex
+// with `--search-mode regex`.
+async fn search_notes(
+    State(state): State<AppState>,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<Vec<Note>>, (StatusCode, String)> {
+    let pattern = match state.search_mode {
+        SearchMode::Substring => regex::escape(&query.q),
+        SearchMode::Regex => query.q.clone(),
+    };
+    let pattern = RegexBuilder::new(&pattern)
+        .case_insensitive(state.search_mode == SearchMode::Substring)
+        .build()
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("invalid regex: {e}")))?;
+
+    let notes = state.notes.read().await;
+    Ok(Json(
+        notes
+            .iter()
+            .filter(|note| pattern.is_match(&note.content))
+            .cloned()
+            .collect(),
+    ))
+}
+
+// GET /notes/export?format=markdown|json
+// All notes, archived ones included, as a file download. The Markdown export
+// is exactly what `notes.md` would contain.
+async fn export_notes(
+    State(state): State<AppState>,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    let notes = state.notes.read().await;
+    let (content_type, filename, body) = match query.format {
+        ExportFormat::Markdown => (
+            "text/markdown; charset=utf-8",
+            "notes.md",
+            notes.iter().map(note_block).collect::<String>(),
+        ),
+        ExportFormat::Json => {
+            let exported = notes
+                .iter()
+                .map(|note| ExportedNote {
+                    id: note.id,
+                    timestamp: &note.timestamp,
+                    content: &note.content,
+                    tags: &note.tags,
+                })
+                .collect::<Vec<_>>();
+            let json = serde_json::to_string_pretty(&exported)
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+            ("application/json", "notes.json", json)
+        }
+    };
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{filename}\""),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+// GET /notes/raw
+// All notes in the format of `notes.md`, shown rather than downloaded.
+async fn get_raw_notes(State(state): State<AppState>) -> Response {
+    let notes = state.notes.read().await;
+    (
+        [
+            (header::CONTENT_TYPE, "text/markdown; charset=utf-8"),
+            (header::CONTENT_DISPOSITION, "inline; filename=\"notes.md\""),
+        ],
+        notes.iter().map(note_block).collect::<String>(),
+    )
+        .into_response()
+}
+
+// GET /notes/:id/raw
+// The content of a note, without its timestamp and metadata.
+async fn get_raw_note(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+) -> Result<Response, (StatusCode, String)> {
+    let notes = state.notes.read().await;
+    let Some(index) = state.note_index(&notes, id) else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("request for non-existent note #{id}"),
+        ));
+    };
+
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                "text/markdown; charset=utf-8".to_string(),
+            ),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("inline; filename=\"note-{id}.md\""),
+            ),
+        ],
+        notes[index].content.clone(),
+    )
+        .into_response())
+}
+
+// GET /downloads
+// The queue, and the downloads that haven't finished yet with the id of their
+// note.
+async fn get_downloads(State(state): State<AppState>) -> Json<DownloadsResponse> {
+    let notes = state.notes.read().await;
+    let downloads = state
+        .download_tracker
+        .unfinished()
+        .into_iter()
+        .filter_map(|(key, download)| {
+            let note = notes.iter().find(|note| note.key == key)?;
+            Some(NoteDownload {
+                note_id: note.id,
+                download,
+            })
+        })
+        .collect();
+
+    Json(DownloadsResponse {
+        queue: state.downloads.status(),
+        downloads,
+    })
+}
+
+// GET /downloads/:id
+// How the downloads of the links of a note went.
+async fn get_note_downloads(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+) -> Result<Json<Vec<DownloadStatus>>, (StatusCode, String)> {
+    let notes = state.notes.read().await;
+    let Some(index) = state.note_index(&notes, id) else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("request for non-existent note #{id}"),
+        ));
+    };
+
+    Ok(Json(state.download_tracker.for_note(notes[index].key)))
+}
+
+// GET /events
+// Server-sent events named after the change (`created`, `updated` or
+// `deleted`) with a JSON `NoteEvent` for every note created, updated or
+// deleted, so clients can refresh without polling. Subscribers too slow to
+// keep up miss events rather than holding up changes.
+async fn get_events(
+    State(state): State<AppState>,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let events = stream::unfold(state.events.subscribe(), |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    let event = Event::default().event(event.event.name()).json_data(event);
+                    return Some((event, receiver));
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Event subscriber fell behind, skipped {missed} events");
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    // tells clients how soon to reconnect when the connection drops
+    let retry = stream::once(async { Ok(Event::default().retry(SSE_RETRY)) });
+
+    Sse::new(retry.chain(events)).keep_alive(KeepAlive::default())
+}
+
+// GET /api/attachments
+// Every file in `attachments` and its subdirectories, e.g. for a file picker.
+// (`/attachments` itself serves the files.)
+async fn get_attachments(State(state): State<AppState>) -> Json<Vec<AttachmentInfo>> {
+    let mut attachments = Vec::new();
+    list_attachments(&state, &PathBuf::from("attachments"), &mut attachments);
+    attachments.sort_by(|a, b| a.path.cmp(&b.path));
+    Json(attachments)
+}
+
+// Hidden files, like the attachment index, are left out.
+fn list_attachments(state: &AppState, dir: &PathBuf, attachments: &mut Vec<AttachmentInfo>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') {
+            continue;
+        }
+        if path.is_dir() {
+            list_attachments(state, &path, attachments);
+            continue;
+        }
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        let created = metadata
+            .created()
+            .or_else(|_| metadata.modified())
+            .map(DateTime::<Utc>::from)
+            .unwrap_or_else(|_| Utc::now());
+
+        attachments.push(AttachmentInfo {
+            path: format!("/{}", path.to_string_lossy().replace('\\', "/")),
+            size_bytes: metadata.len(),
+            created_at: format_timestamp(created, state.timezone, &state.timestamp_format),
+            mime_type: mime_guess::from_path(&path)
+                .first_or_octet_stream()
+                .to_string(),
+            name,
+        });
+    }
+}
+
+// DELETE /api/attachments/:filename
+// Only uploads (files directly in `attachments`) can be deleted. Notes linking
+// to the file are left alone, but reported.
+async fn delete_attachment(
+    State(state): State<AppState>,
+    Path(filename): Path<String>,
+) -> Result<Response, (StatusCode, String)> {
+    if filename.is_empty()
+        || filename.starts_with('.')
+        || filename.contains(['/', '\\'])
+        || std::path::Path::new(&filename).is_absolute()
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("invalid attachment name {filename:?}"),
+        ));
+    }
+
+    // held while updating the attachment index, see `record_attachment_note`
+    let notes = state.notes.write().await;
+    let path = PathBuf::from("attachments").join(&filename);
+    if !path.is_file() {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("no attachment named {filename:?}"),
+        ));
+    }
+    fs::remove_file(&path).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    forget_attachment_note(&filename)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    info!("Attachment deleted: {}", filename);
+    state.commit_changes(format!("Delete attachment {filename}"));
+
+    let url = format!("/attachments/{filename}");
+    let linked_from = notes
+        .iter()
+        .filter(|note| note.content.contains(&url))
+        .map(|note| note.id)
+        .collect::<Vec<_>>();
+    if linked_from.is_empty() {
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    }
+
+    Ok(Json(DeletedAttachment {
+        warning: format!("{} notes still link to {url}", linked_from.len()),
+        deleted: filename,
+        linked_from,
+    })
+    .into_response())
+}
+
+// GET /backups
+// Backups written with `--backup-dir`, oldest first.
+async fn get_backups(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<Backup>>, (StatusCode, String)> {
+    let Some(dir) = &state.backup_dir else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "backups are disabled, see --backup-dir".to_string(),
+        ));
+    };
+    let names = match backup_files(dir) {
+        Ok(names) => names,
+        // no backup has been written yet
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    };
+
+    let backups = names
+        .into_iter()
+        .filter_map(|name| {
+            let metadata = fs::metadata(dir.join(&name)).ok()?;
+            let modified = DateTime::<Utc>::from(metadata.modified().ok()?);
+            Some(Backup {
+                timestamp: format_timestamp(modified, state.timezone, &state.timestamp_format),
+                size_bytes: metadata.len(),
+                name,
+            })
+        })
+        .collect();
+    Ok(Json(backups))
+}
+
+// GET /tags
+// Every tag used by a note, sorted.
+async fn get_tags(State(state): State<AppState>) -> Json<Vec<String>> {
+    let notes = state.notes.read().await;
+    let tags = notes
+        .iter()
+        .flat_map(|note| note.tags.iter().cloned())
+        .collect::<BTreeSet<_>>();
+
+    Json(tags.into_iter().collect())
+}
+
+// GET /notes/timeline
+// Notes grouped by the day of their timestamp, newest day first. Notes within
+// a day keep their usual order.
+async fn get_timeline(
+    State(state): State<AppState>,
+    Query(query): Query<TimelineQuery>,
+) -> Result<Json<Vec<TimelineDay>>, (StatusCode, String)> {
+    let from = query.from.as_deref().map(parse_day).transpose()?;
+    let to = query.to.as_deref().map(parse_day).transpose()?;
+
+    let mut days: BTreeMap<NaiveDate, Vec<Note>> = BTreeMap::new();
+    for note in state.notes.read().await.iter() {
+        let Some(day) = parse_timestamp(&note.timestamp, &state.timestamp_format)
+            .map(|timestamp| timestamp.date())
+        else {
+            continue;
+        };
+        if from.is_some_and(|from| day < from) || to.is_some_and(|to| day > to) {
+            continue;
+        }
+        days.entry(day).or_default().push(note.clone());
+    }
+
+    Ok(Json(
+        days.into_iter()
+            .rev()
+            .map(|(date, notes)| TimelineDay {
+                date: date.to_string(),
+                notes,
+            })
+            .collect(),
+    ))
+}
+
+fn parse_day(day: &str) -> Result<NaiveDate, (StatusCode, String)> {
+    NaiveDate::parse_from_str(day, "%Y-%m-%d").map_err(|_| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Invalid date {day:?}, expected YYYY-MM-DD"),
+        )
+    })
+}
+
+// GET /notes/stats
+async fn get_stats(State(state): State<AppState>) -> Json<NoteStats> {
+    let all_notes = state.notes.read().await;
+    let notes = all_notes
+        .iter()
+        .map(|note| NoteSize {
+            id: note.id,
+            content_bytes: note.content.len(),
+            html_bytes: note.html.len(),
+        })
+        .collect::<Vec<_>>();
+    let total_words = all_notes
+        .iter()
+        .map(|note| note.content.split_whitespace().count())
+        .sum();
+    // notes are usually in creation order, but imports can mix them up
+    let dated = all_notes
+        .iter()
+        .filter_map(|note| {
+            parse_timestamp(&note.timestamp, &state.timestamp_format).map(|time| (time, note))
+        })
+        .collect::<Vec<_>>();
+    let oldest_note = dated
+        .iter()
+        .min_by_key(|(time, _)| *time)
+        .map(|(_, note)| note.timestamp.clone());
+    let newest_note = dated
+        .iter()
+        .max_by_key(|(time, _)| *time)
+        .map(|(_, note)| note.timestamp.clone());
+    let total_notes = all_notes.len();
+    drop(all_notes);
+
+    let (attachments, total_attachments) = state.attachment_usage(ATTACHMENTS_USAGE_TTL);
+
+    Json(NoteStats {
+        total_notes,
+        total_words,
+        oldest_note,
+        newest_note,
+        content_bytes: notes.iter().map(|n| n.content_bytes).sum(),
+        html_bytes: notes.iter().map(|n| n.html_bytes).sum(),
+        notes,
+        attachments_bytes: attachments.values().sum(),
+        total_attachments,
+        attachments,
+    })
+}
+
+// GET /notes/:id
+async fn get_note_by_id(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let notes = state.notes.read().await;
+    let Some(index) = state.note_index(&notes, id) else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("request for non-existent note #{id}"),
+        ));
+    };
+
+    Ok(Json(notes[index].clone()))
+}
+
+// GET /notes/today
+async fn get_notes_today(State(state): State<AppState>) -> Json<Vec<Note>> {
+    Json(recent_notes(&state, 1).await)
+}
+
+// GET /notes/week
+async fn get_notes_week(State(state): State<AppState>) -> Json<Vec<Note>> {
+    Json(recent_notes(&state, 7).await)
+}
+
+// GET /notes/month
+async fn get_notes_month(State(state): State<AppState>) -> Json<Vec<Note>> {
+    Json(recent_notes(&state, 30).await)
+}
+
+// The notes created in the last `days` days, today included, oldest first.
+// Archived notes are left out like in `GET /notes`.
+async fn recent_notes(state: &AppState, days: u64) -> Vec<Note> {
+    let first_day = state.today() - Days::new(days - 1);
+    let notes = state.notes.read().await;
+    let mut recent = notes
+        .iter()
+        .filter(|note| !note.archived)
+        .filter_map(|note| {
+            parse_timestamp(&note.timestamp, &state.timestamp_format)
+                .filter(|time| time.date() >= first_day)
+                .map(|time| (time, note))
+        })
+        .collect::<Vec<_>>();
+    recent.sort_by_key(|(time, _)| *time);
+    recent.into_iter().map(|(_, note)| note.clone()).collect()
+}
+
+// GET /notes/calendar
+// Number of notes created on each day, e.g. `{"2024-01-31": 3}`, for a
+// calendar heatmap. Days without notes are left out.
+async fn get_notes_calendar(State(state): State<AppState>) -> Json<BTreeMap<String, usize>> {
+    let notes = state.notes.read().await;
+    let mut calendar = BTreeMap::new();
+    for note in notes.iter().filter(|note| !note.archived) {
+        if let Some(time) = parse_timestamp(&note.timestamp, &state.timestamp_format) {
+            *calendar
+                .entry(time.date().format("%Y-%m-%d").to_string())
+                .or_default() += 1;
+        }
+    }
+    Json(calendar)
+}
+
+// GET /notes/random
+// A note picked at random, e.g. for reviewing old notes. Archived notes are
+// left out like in `GET /notes`.
+async fn get_random_note(
+    State(state): State<AppState>,
+    Query(query): Query<RandomNoteQuery>,
+) -> Result<Json<Note>, (StatusCode, String)> {
+    let notes = state.notes.read().await;
+    let candidates = notes
+        .iter()
+        .filter(|note| !note.archived)
+        .filter(|note| query.tag.as_ref().is_none_or(|tag| note.tags.contains(tag)))
+        .collect::<Vec<_>>();
+    if candidates.is_empty() {
+        return Err((StatusCode::NOT_FOUND, "no notes to pick from".to_string()));
+    }
+    Ok(Json(candidates[random_below(candidates.len())].clone()))
+}
+
+// GET /notes/duplicates
+// Groups of ids of notes with the same content, or with `?threshold=0.9`
+// also of notes with nearly the same words.
+async fn get_duplicates(
+    State(state): State<AppState>,
+    Query(query): Query<DuplicatesQuery>,
+) -> Result<Json<Vec<Vec<usize>>>, (StatusCode, String)> {
+    let notes = state.notes.read().await;
+    let groups = duplicate_groups(&notes, query.threshold)?;
+    Ok(Json(
+        groups
+            .into_iter()
+            .map(|group| group.into_iter().map(|index| notes[index].id).collect())
+            .collect(),
+    ))
+}
+
+// DELETE /notes/duplicates
+// Deletes all but the oldest (or with `?keep=newest` the newest) note of every
+// group `GET /notes/duplicates` returns, and returns the ids of the deleted
+// notes as they were before.
+async fn delete_duplicates(
+    State(state): State<AppState>,
+    Query(query): Query<DuplicatesQuery>,
+) -> Result<Json<Vec<usize>>, (StatusCode, String)> {
+    let mut notes = state.notes.write().await;
+    let groups = duplicate_groups(&notes, query.threshold)?;
+
+    let mut deleted = Vec::new();
+    for group in groups {
+        let created = |index: &usize| {
+            let note = &notes[*index];
+            (
+                parse_timestamp(&note.timestamp, &state.timestamp_format),
+                note.id,
+            )
+        };
+        let kept = match query.keep {
+            Keep::Oldest => group.iter().copied().min_by_key(created),
+            Keep::Newest => group.iter().copied().max_by_key(created),
+        };
+        deleted.extend(group.into_iter().filter(|index| Some(*index) != kept));
+    }
+    deleted.sort_unstable();
+    if deleted.is_empty() {
+        return Ok(Json(Vec::new()));
+    }
+
+    let mut remaining = Vec::with_capacity(notes.len() - deleted.len());
+    for (index, note) in notes.iter().enumerate() {
+        if deleted.binary_search(&index).is_err() {
+            remaining.push(note.clone());
+        }
+    }
+    // ids are positional
+    for (index, note) in remaining.iter_mut().enumerate() {
+        note.id = state.id_offset + index;
+    }
+    state
+        .save_notes_after_delete(&remaining)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    *notes = remaining;
+    drop(notes);
+
+    let deleted = deleted
+        .into_iter()
+        .map(|index| state.id_offset + index)
+        .collect::<Vec<_>>();
+    info!("Deleted {} duplicate notes", deleted.len());
+    state.commit_changes(format!("Delete {} duplicate notes", deleted.len()));
+    // from the last, so every id is right at the time of its deletion
+    for id in deleted.iter().rev() {
+        state.notify(NoteEventKind::Deleted, *id);
+    }
+
+    Ok(Json(deleted))
+}
+
+// Groups of positions of duplicate notes, each in order and with at least
+// two notes. Notes are in one group if any two of them are duplicates.
+fn duplicate_groups(
+    notes: &[Note],
+    threshold: Option<f64>,
+) -> Result<Vec<Vec<usize>>, (StatusCode, String)> {
+    if threshold.is_some_and(|threshold| !(threshold > 0.0 && threshold <= 1.0)) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "threshold must be above 0 and at most 1".to_string(),
+        ));
+    }
+
+    // each note points to another note of its group, or to itself
+    let mut parent = (0..notes.len()).collect::<Vec<_>>();
+    fn root(parent: &mut [usize], mut index: usize) -> usize {
+        while parent[index] != index {
+            parent[index] = parent[parent[index]];
+            index = parent[index];
+        }
+        index
+    }
+    let mut join = |a: usize, b: usize| {
+        let (a, b) = (root(&mut parent, a), root(&mut parent, b));
+        parent[a.max(b)] = a.min(b);
+    };
+
+    let mut first_with_content = HashMap::new();
+    for (index, note) in notes.iter().enumerate() {
+        if let Some(&first) = first_with_content.get(note.content.trim()) {
+            join(first, index);
+        } else {
+            first_with_content.insert(note.content.trim(), index);
+        }
+    }
+
+    if let Some(threshold) = threshold {
+        let words = notes
+            .iter()
+            .map(|note| word_set(&note.content))
+            .collect::<Vec<_>>();
+        for a in 0..notes.len() {
+            for b in a + 1..notes.len() {
+                if jaccard_similarity(&words[a], &words[b]) >= threshold {
+                    join(a, b);
+                }
+            }
+        }
+    }
+
+    let mut groups = BTreeMap::<usize, Vec<usize>>::new();
+    for index in 0..notes.len() {
+        groups
+            .entry(root(&mut parent, index))
+            .or_default()
+            .push(index);
+    }
+    Ok(groups
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .collect())
+}
+
+// GET /notes/by-slug/:slug
+async fn get_note_by_slug(
+    State(state): State<AppState>,
+    Path(slug): Path<String>,
+) -> Result<Json<Note>, (StatusCode, String)> {
+    let notes = state.notes.read().await;
+    notes
+        .iter()
+        .find(|note| note.slug.as_deref() == Some(slug.as_str()))
+        .map(|note| Json(note.clone()))
+        .ok_or((StatusCode::NOT_FOUND, format!("no note with slug {slug:?}")))
+}
+
+// PUT /notes/:id/slug
+// Replaces the slug a note got from its first line, e.g. with `"reading-list"`.
+async fn set_note_slug(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+    Json(slug): Json<String>,
+) -> Result<Json<Note>, (StatusCode, String)> {
+    if !valid_slug(&slug) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "invalid slug {slug:?}, use up to {MAX_SLUG_LENGTH} lowercase letters, digits and -"
+            ),
+        ));
+    }
+
+    let mut notes = state.notes.write().await;
+    let Some(index) = state.note_index(&notes, id) else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("request for non-existent note #{id}"),
+        ));
+    };
+    if let Some(other) = notes
+        .iter()
+        .find(|note| note.id != id && note.slug.as_deref() == Some(slug.as_str()))
+    {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("note #{} already has the slug {slug:?}", other.id),
+        ));
+    }
+
+    notes[index].slug = Some(slug);
+    state
+        .save_notes(&notes)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    info!("Note updated: {}", id);
+    state.commit_changes(format!("Set slug of note {id}"));
+    state.notify(NoteEventKind::Updated, id);
+
+    Ok(Json(notes[index].clone()))
+}
+
+// POST /notes/batch
+// The notes with the given ids, in the order asked for. Ids that don't exist
+// are left out.
+async fn get_notes_by_ids(
+    State(state): State<AppState>,
+    Json(ids): Json<Vec<usize>>,
+) -> Json<Vec<Note>> {
+    let notes = state.notes.read().await;
+    Json(
+        ids.into_iter()
+            .filter_map(|id| state.note_index(&notes, id))
+            .map(|index| notes[index].clone())
+            .collect(),
+    )
+}
+
+// GET /notes/:id/block
+// The note exactly as it is written to the notes file, for debugging the file
+// format.
+async fn get_note_block(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let notes = state.notes.read().await;
+    let Some(index) = state.note_index(&notes, id) else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("request for non-existent note #{id}"),
+        ));
+    };
+
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        note_block(&notes[index]),
+    ))
+}
+
+// GET /notes/:id/verify
+// Reads the notes from disk again and compares the checksum of the note there
+// with the one of the note in memory and with the one saved alongside it, to
+// notice damage to the notes file. A notes file that can't be read is damaged
+// as a whole.
+async fn verify_note(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+) -> Result<Json<Verification>, (StatusCode, String)> {
+    let notes = state.notes.read().await;
+    let Some(index) = state.note_index(&notes, id) else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("request for non-existent note #{id}"),
+        ));
+    };
+
+    let expected = content_checksum(&notes[index].content);
+    let on_disk = match read_notes_file(
+        &state.notes_file,
+        state.notes_format,
+        state.id_offset,
+        &state.timestamp_format,
+        state.timezone,
+    ) {
+        Ok(on_disk) => on_disk,
+        Err(e) => {
+            warn!(
+                "could not read notes from {}: {e}",
+                state.notes_file.display()
+            );
+            Vec::new()
+        }
+    };
+    let saved = on_disk.get(index);
+    let actual = saved.map(|note| content_checksum(&note.content));
+    let stored = saved.and_then(|note| note.checksum.clone());
+
+    Ok(Json(Verification {
+        valid: actual.as_ref() == Some(&expected)
+            && stored
+                .as_ref()
+                .is_none_or(|stored| Some(stored) == actual.as_ref()),
+        expected,
+        actual,
+        stored,
+    }))
+}
+
+// GET /notes/:id/links
+// URLs of the links in a note, autolinks included.
+async fn get_note_links(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+) -> Result<Json<Vec<String>>, (StatusCode, String)> {
+    let notes = state.notes.read().await;
+    let Some(index) = state.note_index(&notes, id) else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("request for non-existent note #{id}"),
+        ));
+    };
+
+    Ok(Json(extract_links(&notes[index].content)))
+}
+
+// GET /notes/:id/analytics
+async fn get_note_analytics(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+    Query(query): Query<AnalyticsQuery>,
+) -> Result<Json<NoteAnalytics>, (StatusCode, String)> {
+    let notes = state.notes.read().await;
+    let Some(index) = state.note_index(&notes, id) else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("request for non-existent note #{id}"),
+        ));
+    };
+
+    let word_frequency = word_frequency(&notes[index].content, query.stop_words);
+    let (words, characters) =
+        word_frequency
+            .iter()
+            .fold((0, 0), |(words, characters), (word, count)| {
+                (words + count, characters + word.chars().count() * count)
+            });
+
+    Ok(Json(NoteAnalytics {
+        unique_words: word_frequency.len(),
+        avg_word_length: if words == 0 {
+            0.0
+        } else {
+            characters as f64 / words as f64
+        },
+        word_frequency,
+    }))
+}
+
+// GET /notes/:id/links/check
+// Sends a HEAD request to every web link of a note, a few at a time, to find
+// links that stopped working. Other links (e.g. to attachments) are left out.
+async fn check_note_links(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+) -> Result<Json<Vec<LinkCheck>>, (StatusCode, String)> {
+    let notes = state.notes.read().await;
+    let Some(index) = state.note_index(&notes, id) else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("request for non-existent note #{id}"),
+        ));
+    };
+    let links = extract_links(&notes[index].content);
+    drop(notes);
+
+    let permits = Semaphore::new(LINK_CHECK_CONCURRENCY);
+    let checks = links
+        .into_iter()
+        .filter(|url| url.starts_with("http://") || url.starts_with("https://"))
+        .map(|url| {
+            let permits = &permits;
+            async move {
+                // the semaphore is never closed
+                let _permit = permits.acquire().await.unwrap();
+                let status = head_status(&url).await;
+                LinkCheck {
+                    url,
+                    status,
+                    reachable: status.is_some_and(|status| status < 400),
+                }
+            }
+        });
+
+    Ok(Json(future::join_all(checks).await))
+}
+
+// PUT /notes/:id
+// Replaces the content of a note, keeping its timestamp and metadata. Takes
+// the same JSON body as `POST /notes`, or the new content as a `text/plain`
+// or `text/markdown` body, which is easier to send from scripts.
+async fn update_note(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Note>, (StatusCode, String)> {
+    let content = match media_type(&headers).as_str() {
+        "text/plain" | "text/markdown" => text_body(&body)?,
+        json if json == "application/json" || json.ends_with("+json") => {
+            note_body(Json::from_bytes(&body))?.into_content()
+        }
+        other => {
+            return Err((
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                format!("expected JSON, text/plain or text/markdown, not {other:?}"),
+            ))
+        }
+    };
+    replace_note_content(&state, id, &content).await
+}
+
+// PUT /notes/:id/content
+// Like `PUT /notes/:id`, but only with a `text/plain` body.
+async fn update_note_content(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Note>, (StatusCode, String)> {
+    let media_type = media_type(&headers);
+    if media_type != "text/plain" {
+        return Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!("expected text/plain, not {media_type:?}; send JSON to PUT /notes/{id}"),
+        ));
+    }
+    replace_note_content(&state, id, &text_body(&body)?).await
+}
+
+// The media type of the request body, e.g. `text/plain` for
+// `Text/Plain; charset=utf-8`.
+fn media_type(headers: &HeaderMap) -> String {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase()
+}
+
+fn text_body(body: &[u8]) -> Result<String, (StatusCode, String)> {
+    String::from_utf8(body.to_vec()).map_err(|_| {
+        (
+            StatusCode::BAD_REQUEST,
+            "the body isn't valid UTF-8".to_string(),
+        )
+    })
+}
+
+// Replaces the content of note `id`, keeping its timestamp and metadata.
+async fn replace_note_content(
+    state: &AppState,
+    id: usize,
+    content: &str,
+) -> Result<Json<Note>, (StatusCode, String)> {
+    state.check_note_size(content)?;
+    let (content, links_to_download) = prepare_content(state, content);
+
+    let mut notes = state.notes.write().await;
+    let Some(index) = state.note_index(&notes, id) else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("request for non-existent note #{id}"),
+        ));
+    };
+
+    let note = &mut notes[index];
+    note.content = content;
+    note.modified = Some(state.now());
+    note.render(&state.render_config);
+    let note = note.clone();
+
+    state
+        .save_notes(&notes)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    drop(notes);
+
+    info!("Note updated: {}", id);
+    state.commit_changes(format!("Update note {id}"));
+    state.notify(NoteEventKind::Updated, id);
+
+    spawn_downloads(state, note.key, links_to_download);
+
+    Ok(Json(note))
+}
+
+// POST /notes/:id/pin
+async fn pin_note(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+) -> Result<Json<Note>, (StatusCode, String)> {
+    set_note_flag(&state, id, "Pin", |note| note.pinned = true).await
+}
+
+// DELETE /notes/:id/pin
+async fn unpin_note(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+) -> Result<Json<Note>, (StatusCode, String)> {
+    set_note_flag(&state, id, "Unpin", |note| note.pinned = false).await
+}
+
+// PUT /notes/:id/archive
+async fn archive_note(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+) -> Result<Json<Note>, (StatusCode, String)> {
+    set_note_flag(&state, id, "Archive", |note| note.archived = true).await
+}
+
+// PUT /notes/:id/unarchive
+async fn unarchive_note(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+) -> Result<Json<Note>, (StatusCode, String)> {
+    set_note_flag(&state, id, "Unarchive", |note| note.archived = false).await
+}
+
+// Applies `update` to a note's flags and saves the notes; `action` names the
+// change in logs and commit messages.
+async fn set_note_flag(
+    state: &AppState,
+    id: usize,
+    action: &str,
+    update: impl FnOnce(&mut Note),
+) -> Result<Json<Note>, (StatusCode, String)> {
+    let mut notes = state.notes.write().await;
+    let Some(index) = state.note_index(&notes, id) else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("request for non-existent note #{id}"),
+        ));
+    };
+
+    update(&mut notes[index]);
+    state
+        .save_notes(&notes)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    info!("{action} note {id}");
+    state.commit_changes(format!("{action} note {id}"));
+    state.notify(NoteEventKind::Updated, id);
+
+    Ok(Json(notes[index].clone()))
+}
+
+// PATCH /notes/:id
+// Concurrent patches are applied one after the other as each one holds the
+// notes lock, so appending from several scripts at once doesn't lose text.
+async fn patch_note(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+    Json(patch): Json<NotePatch>,
+) -> Result<Json<Note>, (StatusCode, String)> {
+    let mut notes = state.notes.write().await;
+    let Some(index) = state.note_index(&notes, id) else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("request for non-existent note #{id}"),
+        ));
+    };
+
+    if let Some(classes) = &patch.classes {
+        if let Some(class) = classes.iter().find(|class| !valid_class_name(class)) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("invalid class name {class:?}, use letters, digits, - and _"),
+            ));
+        }
+    }
+    if let Some(text) = &patch.append {
+        state.check_note_size(&format!("{}\n{}", notes[index].content, text))?;
+    }
+
+    if let Some(priority) = patch.priority {
+        notes[index].priority = priority;
+    }
+    if let Some(classes) = patch.classes {
+        notes[index].classes = classes;
+    }
+    let mut links_to_download = Vec::new();
+    if let Some(text) = patch.append {
+        let (text, links) = prepare_content(&state, &text);
+        let note = &mut notes[index];
+        note.content = format!("{}\n{}", note.content, text);
+        note.modified = Some(state.now());
+        note.render(&state.render_config);
+        links_to_download = links;
+    }
+
+    state
+        .save_notes(&notes)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    info!("Note updated: {}", id);
+    state.commit_changes(format!("Update note {id}"));
+    state.notify(NoteEventKind::Updated, id);
+
+    spawn_downloads(&state, notes[index].key, links_to_download);
+
+    Ok(Json(notes[index].clone()))
+}
+
+// DELETE /notes/:id
+async fn delete_note_by_id(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let mut notes = state.notes.write().await;
+    let Some(index) = state.note_index(&notes, id) else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("request for non-existent note #{id}"),
+        ));
+    };
+
+    notes.remove(index);
+    // ids are positional, so every note after the deleted one moves up
+    for note in &mut notes[index..] {
+        note.id -= 1;
+    }
+
+    if let Err(e) = state.save_notes_after_delete(&notes) {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+    }
+
+    info!("Note deleted: {}", id);
+    state.commit_changes(format!("Delete note {id}"));
+    state.notify(NoteEventKind::Deleted, id);
+
+    // TODO return the deleted note, maybe?
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// POST /notes
+async fn save_note(
+    State(state): State<AppState>,
+    body: Result<Json<NoteBody>, JsonRejection>,
+) -> Result<(), (StatusCode, String)> {
+    let (content, tags) = note_body(body)?.into_parts();
+    if let Some(tag) = tags.iter().find(|tag| !valid_tag(tag)) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("invalid tag {tag:?}, use letters, digits, - and _"),
+        ));
+    }
+    create_note(&state, &content, tags).await?;
+
+    Ok(())
+}
+
+fn note_body(
+    body: Result<Json<NoteBody>, JsonRejection>,
+) -> Result<NoteBody, (StatusCode, String)> {
+    let Json(body) = body.map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!(
+                "note body must be a JSON string or an object like {{\"content\": \"...\"}}: {}",
+                e.body_text()
+            ),
+        )
+    })?;
+
+    Ok(body)
+}
+
+// Appends a new note to the notes file and starts downloading its links.
+// `--auto-tag` rules add their tags to the given ones.
+async fn create_note(
+    state: &AppState,
+    content: &str,
+    tags: Vec<String>,
+) -> Result<Note, (StatusCode, String)> {
+    state.check_note_size(content)?;
+    let (content, links_to_download) = prepare_content(state, content);
+
+    let timestamp = state.now();
+    let mut notes = state.notes.write().await;
+    let mut note = Note::new(state.next_note_id(&notes), timestamp.clone(), content);
+    note.slug = Some(unique_slug(&notes, &note.content));
+    note.render(&state.render_config);
+    for tag in tags {
+        if !note.tags.contains(&tag) {
+            note.tags.push(tag);
+        }
+    }
+    for rule in &state.auto_tags {
+        if rule.pattern.is_match(&note.content) && !note.tags.contains(&rule.tag) {
+            note.tags.push(rule.tag.clone());
+        }
+    }
+
+    notes.push(note.clone());
+    state
+        .append_notes(&notes, 1)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    drop(notes);
+
+    info!("Note created: {}", timestamp);
+    state.commit_changes(format!("Add note {timestamp}"));
+    state.notify(NoteEventKind::Created, note.id);
+
+    spawn_downloads(state, note.key, links_to_download);
+
+    Ok(note)
+}
+
+// POST /notes/:id/duplicate
+// Creates a copy of a note with its tags, e.g. of a template to fill in.
+async fn duplicate_note(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+) -> Result<(StatusCode, Json<Note>), (StatusCode, String)> {
+    let notes = state.notes.read().await;
+    let Some(index) = state.note_index(&notes, id) else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("request for non-existent note #{id}"),
+        ));
+    };
+    let content = copy_title(&notes[index].content);
+    let tags = notes[index].tags.clone();
+    drop(notes);
+
+    let note = create_note(&state, &content, tags).await?;
+    Ok((StatusCode::CREATED, Json(note)))
+}
+
+// `content` with "Copy of " at the start of its first line, after the `#`s
+// if that's a heading.
+fn copy_title(content: &str) -> String {
+    let hashes = content.len() - content.trim_start_matches('#').len();
+    match content[hashes..].strip_prefix(' ') {
+        Some(title) if hashes > 0 => format!("{} Copy of {title}", &content[..hashes]),
+        _ => format!("Copy of {content}"),
+    }
+}
+
+// GET /drafts
+async fn get_drafts(State(state): State<AppState>) -> Json<Vec<Draft>> {
+    Json(
+        state
+            .drafts
+            .lock()
+            .unwrap()
+            .drafts
+            .values()
+            .cloned()
+            .collect(),
+    )
+}
+
+// POST /drafts
+// Drafts are kept in memory only, they are never written to the notes file.
+async fn create_draft(
+    State(state): State<AppState>,
+    body: Result<Json<NoteBody>, JsonRejection>,
+) -> Result<(StatusCode, Json<Draft>), (StatusCode, String)> {
+    let content = note_body(body)?.into_content();
+
+    let mut drafts = state.drafts.lock().unwrap();
+    let id = drafts.next_id;
+    drafts.next_id += 1;
+    let timestamp = state.now();
+    let draft = Draft {
+        id,
+        created: timestamp.clone(),
+        updated: timestamp,
+        content,
+    };
+    drafts.drafts.insert(id, draft.clone());
+
+    Ok((StatusCode::CREATED, Json(draft)))
+}
+
+// PUT /drafts/:id
+async fn update_draft(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+    body: Result<Json<NoteBody>, JsonRejection>,
+) -> Result<Json<Draft>, (StatusCode, String)> {
+    let content = note_body(body)?.into_content();
+
+    let mut drafts = state.drafts.lock().unwrap();
+    let draft = drafts
+        .drafts
+        .get_mut(&id)
+        .ok_or((StatusCode::NOT_FOUND, format!("no draft #{id}")))?;
+    draft.content = content;
+    draft.updated = state.now();
+
+    Ok(Json(draft.clone()))
+}
+
+// DELETE /drafts/:id
+async fn delete_draft(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    match state.drafts.lock().unwrap().drafts.remove(&id) {
+        Some(_) => Ok(StatusCode::NO_CONTENT),
+        None => Err((StatusCode::NOT_FOUND, format!("no draft #{id}"))),
+    }
+}
+
+// POST /drafts/:id/publish
+// Turns the draft into a regular note and discards it.
+async fn publish_draft(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+) -> Result<Json<Note>, (StatusCode, String)> {
+    let draft = state
+        .drafts
+        .lock()
+        .unwrap()
+        .drafts
+        .remove(&id)
+        .ok_or((StatusCode::NOT_FOUND, format!("no draft #{id}")))?;
+
+    match create_note(&state, &draft.content, Vec::new()).await {
+        Ok(note) => Ok(Json(note)),
+        Err(e) => {
+            // keep the draft so publishing can be retried
+            state.drafts.lock().unwrap().drafts.insert(id, draft);
+            Err(e)
+        }
+    }
+}
+
+// POST /batch
+// Runs the operations in order against a copy of the notes, so every
+// operation sees the effect of the ones before it (e.g. ids shift after a
+// delete). The notes file is written once at the end; operations that failed
+// are reported and skipped without affecting the others.
+async fn apply_batch(
+    State(state): State<AppState>,
+    Json(ops): Json<Vec<BatchOp>>,
+) -> Result<Json<Vec<BatchResult>>, (StatusCode, String)> {
+    let mut notes = state.notes.write().await;
+    let mut updated = notes.clone();
+    let mut downloads = Vec::new();
+    let mut events = Vec::new();
+    let mut results = Vec::with_capacity(ops.len());
+
+    for op in ops {
+        let result = match op {
+            BatchOp::Create { content } => match state.check_note_size(&content) {
+                Ok(()) => {
+                    let (content, links) = prepare_content(&state, &content);
+                    let timestamp = state.now();
+                    let mut note = Note::new(state.next_note_id(&updated), timestamp, content);
+                    note.slug = Some(unique_slug(&updated, &note.content));
+                    note.render(&state.render_config);
+                    downloads.push((note.key, links));
+                    events.push((NoteEventKind::Created, note.id));
+                    updated.push(note.clone());
+                    Ok(Some(note))
+                }
+                Err((_, error)) => Err(error),
+            },
+            BatchOp::Update { id, content } => match (
+                state.note_index(&updated, id),
+                state.check_note_size(&content),
+            ) {
+                (Some(index), Ok(())) => {
+                    let (content, links) = prepare_content(&state, &content);
+                    let note = &mut updated[index];
+                    note.content = content;
+                    note.modified = Some(state.now());
+                    note.render(&state.render_config);
+                    downloads.push((note.key, links));
+                    events.push((NoteEventKind::Updated, id));
+                    Ok(Some(note.clone()))
+                }
+                (Some(_), Err((_, error))) => Err(error),
+                (None, _) => Err(format!("request for non-existent note #{id}")),
+            },
+            BatchOp::Delete { id } => match state.note_index(&updated, id) {
+                Some(index) => {
+                    updated.remove(index);
+                    for note in &mut updated[index..] {
+                        note.id -= 1;
+                    }
+                    events.push((NoteEventKind::Deleted, id));
+                    Ok(None)
+                }
+                None => Err(format!("request for non-existent note #{id}")),
+            },
+        };
+
+        results.push(match result {
+            Ok(note) => BatchResult {
+                ok: true,
+                note,
+                error: None,
+            },
+            Err(error) => BatchResult {
+                ok: false,
+                note: None,
+                error: Some(error),
+            },
+        });
+    }
+
+    let deleted = events
+        .iter()
+        .any(|(kind, _)| matches!(kind, NoteEventKind::Deleted));
+    if deleted {
+        state.save_notes_after_delete(&updated)
+    } else {
+        state.save_notes(&updated)
+    }
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    *notes = updated;
+    drop(notes);
+
+    info!("Applied batch of {} operations", results.len());
+    state.commit_changes(format!("Apply batch of {} operations", results.len()));
+    for (event, id) in events {
+        state.notify(event, id);
+    }
+
+    for (key, links) in downloads {
+        spawn_downloads(&state, key, links);
+    }
+
+    Ok(Json(results))
+}
+
+// POST /import
+// Meant for loading many notes at once: all notes are appended to the notes
+// file in a single write and, unless `downloads=true` is passed, their `+http`
+// links are left alone so thousands of downloads don't start at once. Links
+// of a single note can be downloaded later with `POST /notes/:id/download`.
+//
+// Imported notes may carry the id they had on the other instance. Appended
+// notes get new ids, and `[[id]]` links between imported notes are rewritten
+// to match. What happens to a note whose id is already taken here depends on
+// `conflict`, see `ImportConflict`.
+async fn import_notes(
+    State(state): State<AppState>,
+    Query(query): Query<ImportQuery>,
+    Json(imported): Json<Vec<ImportedNote>>,
+) -> Result<Json<ImportResult>, (StatusCode, String)> {
+    let result = import(&state, imported, &query).await?;
+    state.commit_changes(format!("Import {} notes", result.imported));
+
+    Ok(Json(result))
+}
+
+// POST /notes/import
+// Merges a notes file (e.g. a backup from `GET /notes/export`) uploaded as the
+// multipart field `file`. Both the Markdown format of `notes.md` and a JSON
+// array of notes are accepted. Notes that are already here, with the same
+// timestamp and content, are skipped; the others keep their metadata.
+async fn import_notes_file(
+    State(state): State<AppState>,
+    mut multipart: Multipart,
+) -> Result<Json<FileImportResult>, (StatusCode, String)> {
+    let bad_request = |e: MultipartError| (StatusCode::BAD_REQUEST, e.body_text());
+    let mut upload = None;
+    while let Some(field) = multipart.next_field().await.map_err(bad_request)? {
+        if field.name() == Some("file") {
+            upload = Some(field.bytes().await.map_err(bad_request)?);
+        }
+    }
+    let upload = upload.ok_or((
+        StatusCode::BAD_REQUEST,
+        "the notes file must be sent in the field \"file\"".to_string(),
+    ))?;
+    let content = String::from_utf8_lossy(&upload);
+
+    let mut result = FileImportResult::default();
+    let uploaded = if content.trim_start().starts_with('[') {
+        let values: Vec<serde_json::Value> = serde_json::from_str(&content)
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("invalid JSON: {e}")))?;
+        values
+            .into_iter()
+            .enumerate()
+            .filter_map(
+                |(index, value)| match serde_json::from_value::<Note>(value) {
+                    Ok(note) => Some(note),
+                    Err(e) => {
+                        result.errors.push(format!("note {index}: {e}"));
+                        None
+                    }
+                },
+            )
+            .collect()
+    } else {
+        parse_note_blocks(&content, &state.timestamp_format, &state.now())
+    };
+
+    let mut notes = state.notes.write().await;
+    let existing = notes
+        .iter()
+        .map(|note| (note.timestamp.clone(), content_hash(&note.content)))
+        .collect::<BTreeSet<_>>();
+    for mut note in uploaded {
+        if note.timestamp.contains('\n')
+            || parse_timestamp(&note.timestamp, &state.timestamp_format).is_none()
+        {
+            result.errors.push(format!(
+                "timestamp {:?} doesn't match the format {:?}",
+                note.timestamp, state.timestamp_format
+            ));
+            continue;
+        }
+        if existing.contains(&(note.timestamp.clone(), content_hash(&note.content))) {
+            result.skipped += 1;
+            continue;
+        }
+        // "---" would be taken for the note separator of the notes file
+        note.content = note.content.replace("---", "<hr>");
+        note.id = state.next_note_id(&notes);
+        note.render(&state.render_config);
+        notes.push(note);
+        result.imported += 1;
+    }
+    // the slugs of the imported notes may be taken
+    assign_slugs(&mut notes);
+
+    state
+        .append_notes(&notes, result.imported)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    drop(notes);
+
+    info!(
+        "Imported {} notes from a file ({} skipped, {} errors)",
+        result.imported,
+        result.skipped,
+        result.errors.len()
+    );
+    if result.imported > 0 {
+        state.commit_changes(format!("Import {} notes", result.imported));
+    }
+
+    Ok(Json(result))
+}
+
+// textpod import --file FILE
+async fn import_file(state: &AppState, file: &PathBuf) {
+    let content = match fs::read_to_string(file) {
+        Ok(content) => content,
+        Err(e) => {
+            error!("could not read {}: {e}", file.display());
+            process::exit(1);
+        }
+    };
+
+    let mut imported = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(line) {
+            Ok(note) => imported.push(note),
+            Err(e) => {
+                error!("{}:{}: {e}", file.display(), number + 1);
+                process::exit(1);
+            }
+        }
+    }
+
+    let query = ImportQuery {
+        downloads: false,
+        conflict: ImportConflict::default(),
+    };
+    match import(state, imported, &query).await {
+        Ok(result) => {
+            if let Some(commit) = state.commit_changes(format!("Import {} notes", result.imported))
+            {
+                let _ = commit.await;
+            }
+        }
+        Err((_, e)) => {
+            error!("{e}");
+            process::exit(1);
+        }
+    }
+}
+
+// Adds the notes to the notes file in a single write, shared by `POST /import`
+// and `textpod import`.
+async fn import(
+    state: &AppState,
+    imported: Vec<ImportedNote>,
+    query: &ImportQuery,
+) -> Result<ImportResult, (StatusCode, String)> {
+    let mut notes = state.notes.write().await;
+    let mut result = ImportResult::default();
+
+    // decide first where every note goes, links are rewritten with the outcome
+    let mut targets = Vec::with_capacity(imported.len());
+    let mut next_index = notes.len();
+    for imported_note in &imported {
+        let existing = imported_note.id.and_then(|id| state.note_index(&notes, id));
+        let target = match (existing, query.conflict) {
+            (Some(_), ImportConflict::Skip) => None,
+            (Some(index), ImportConflict::Overwrite) => Some(index),
+            _ => {
+                next_index += 1;
+                Some(next_index - 1)
+            }
+        };
+        if let (Some(old_id), Some(index)) = (imported_note.id, target) {
+            let new_id = state.id_offset + index;
+            if new_id != old_id {
+                result.remapped.insert(old_id, new_id);
+            }
+        }
+        targets.push(target);
+    }
+
+    let link = Regex::new(r"\[\[(\d+)\]\]").unwrap();
+    let mut updated = notes.clone();
+    let mut downloads = Vec::new();
+    for (imported_note, target) in imported.into_iter().zip(targets) {
+        let Some(index) = target else {
+            result.skipped += 1;
+            continue;
+        };
+
+        state.check_note_size(&imported_note.content)?;
+        let timestamp = match imported_note.timestamp {
+            Some(timestamp)
+                if parse_timestamp(timestamp.trim(), &state.timestamp_format).is_none() =>
+            {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!(
+                        "timestamp {timestamp:?} doesn't match the format {:?}",
+                        state.timestamp_format
+                    ),
+                ));
+            }
+            Some(timestamp) => timestamp.trim().to_string(),
+            None => state.now(),
+        };
+        let content = link.replace_all(&imported_note.content, |caps: &regex::Captures| match caps
+            [1]
+        .parse()
+        .ok()
+        .and_then(|id| result.remapped.get(&id))
+        {
+            Some(new_id) => format!("[[{new_id}]]"),
+            None => caps[0].to_string(),
+        });
+        let (content, links) = if query.downloads {
+            prepare_content(state, &content)
+        } else {
+            // "---" would be taken for the note separator of the notes file
+            (content.replace("---", "<hr>"), Vec::new())
+        };
+
+        let mut note = Note::new(state.id_offset + index, timestamp, content);
+        downloads.push((note.key, links));
+        note.render(&state.render_config);
+        if index < updated.len() {
+            updated[index] = note;
+            result.overwritten.push(state.id_offset + index);
+        } else {
+            updated.push(note);
+            result.added += 1;
+        }
+    }
+
+    assign_slugs(&mut updated);
+    if result.overwritten.is_empty() {
+        state.append_notes(&updated, result.added)
+    } else {
+        state.save_notes(&updated)
+    }
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    *notes = updated;
+    drop(notes);
+
+    result.imported = result.added + result.overwritten.len();
+    info!(
+        "Imported {} notes ({} added, {} overwritten, {} skipped)",
+        result.imported,
+        result.added,
+        result.overwritten.len(),
+        result.skipped
+    );
+
+    for (key, links) in downloads {
+        spawn_downloads(state, key, links);
+    }
+
+    Ok(result)
+}
+
+// POST /notes/:id/download
+// Downloads the `+http` links of a note that were skipped when importing it.
+async fn download_note_links(
+    State(state): State<AppState>,
+    Path(id): Path<usize>,
+) -> Result<Json<Note>, (StatusCode, String)> {
+    let mut notes = state.notes.write().await;
+    let Some(index) = state.note_index(&notes, id) else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("request for non-existent note #{id}"),
+        ));
+    };
+
+    let (content, links) = prepare_content(&state, &notes[index].content);
+    let note = &mut notes[index];
+    note.content = content;
+    note.render(&state.render_config);
+    let note = note.clone();
+
+    state
+        .save_notes(&notes)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    drop(notes);
+
+    state.commit_changes(format!("Download links of note {id}"));
+    spawn_downloads(&state, note.key, links);
+
+    Ok(Json(note))
+}
+
+// Applies the usual transformations to the content of a new or edited note
+// and returns it along with the `+http`, `+ftp` and `+sftp` links that should
+// be downloaded.
+fn prepare_content(state: &AppState, content: &str) -> (String, Vec<String>) {
+    // Replace "---" with "<hr>" in the content
+    let mut content = content.replace("---", "<hr>");
+    let links_to_download: Vec<String> = content
+        .split_whitespace()
+        .filter(|word| {
+            word.starts_with("+http") || word.starts_with("+ftp://") || word.starts_with("+sftp://")
+        })
+        .map(|s| s.to_string())
+        .collect();
+
+    for link in &links_to_download {
+        let url = &link[1..];
+        // previews are added once they've been fetched, see `download_link`
+        // files and audio have no preview, so they are always downloaded
+        let replacement = match state.link_mode {
+            _ if is_ftp_url(url) => local_file_link(url),
+            _ if is_audio_url(url, &state.audio_hosts) => local_audio_link(url),
+            LinkMode::Snapshot => local_copy_link(url),
+            // whether there is a video is only known once yt-dlp tried
+            LinkMode::Preview | LinkMode::Auto | LinkMode::Video => url.to_string(),
+        };
+        content = content.replace(link, &replacement);
+    }
+
+    (content, links_to_download)
+}
+
+fn spawn_downloads(state: &AppState, key: u64, links: Vec<String>) {
+    if links.is_empty() {
+        return;
+    }
+
+    fs::create_dir_all("attachments/webpages").unwrap();
+    fs::create_dir_all("attachments/audio").unwrap();
+    fs::create_dir_all("attachments/video").unwrap();
+    fs::create_dir_all("attachments/ftp").unwrap();
+
+    for link in links {
+        let state = state.clone();
+        state.download_tracker.queue(key, &link[1..]);
+        state.downloads.clone().push(async move {
+            let url = &link[1..];
+            state.download_tracker.start(key, url, state.now());
+            let succeeded = download_link(&state, key, url).await;
+            state
+                .download_tracker
+                .finish(key, url, succeeded, state.now());
+        });
+    }
+}
+
+// Saves a local copy of `url`, fetches a preview of it or downloads its video,
+// depending on `--link-mode`, and updates the note with `key` accordingly.
+// Files and audio are downloaded in every mode. Returns whether that worked.
+async fn download_link(state: &AppState, key: u64, url: &str) -> bool {
+    if is_ftp_url(url) {
+        return download_linked_file(state, key, url).await;
+    }
+    if is_audio_url(url, &state.audio_hosts) {
+        return download_linked_audio(state, key, url).await;
+    }
+
+    match state.link_mode {
+        LinkMode::Snapshot => {
+            if download_local_copy(state, url).await {
+                return true;
+            }
+            let local_copy = format!("([local copy](/{}))", snapshot_path(url));
+            update_linked_note(state, key, |note| {
+                note.content = note.content.replace(&local_copy, "(local copy failed)");
+            })
+            .await;
+            false
+        }
+        LinkMode::Preview | LinkMode::Auto => {
+            info!("Fetching preview: {}", url);
+            if let Some(preview) = fetch_preview(url, state.download_timeout).await {
+                update_linked_note(state, key, |note| note.previews.push(preview)).await;
+                return true;
+            }
+            if state.link_mode == LinkMode::Preview {
+                warn!("No preview found for {}", url);
+                return false;
+            }
+            info!("No preview found for {}, saving a local copy instead", url);
+            add_local_copy(state, key, url).await
+        }
+        LinkMode::Video => {
+            if download_linked_video(state, url).await {
+                update_linked_note(state, key, |note| {
+                    note.content = note.content.replacen(url, &local_video_link(url), 1);
+                })
+                .await;
+                return true;
+            }
+            info!("No video found at {}, saving a local copy instead", url);
+            add_local_copy(state, key, url).await
+        }
+    }
+}
+
+// Saves a local copy of `url` for a link that was left as it is, and links to
+// it from the note with `key` once it's there.
+async fn add_local_copy(state: &AppState, key: u64, url: &str) -> bool {
+    if !download_local_copy(state, url).await {
+        return false;
+    }
+    update_linked_note(state, key, |note| {
+        note.content = note.content.replacen(url, &local_copy_link(url), 1);
+    })
+    .await;
+    true
+}
+
+// Saves the page at `url` with monolith to `snapshot_path(url)`, unless it's
+// there already.
+async fn download_local_copy(state: &AppState, url: &str) -> bool {
+    let filepath = snapshot_path(url);
+    // the file name only depends on the URL, so an existing file is a copy of the same page
+    if !state.refresh_snapshots && PathBuf::from(&filepath).exists() {
+        info!("Reusing local copy of {}: {}", url, filepath);
+        return true;
+    }
+
+    info!("Downloading webpage: {}", url);
+
+    let downloaded = state
+        .retry_policy
+        .run(url, || {
+            download_webpage(url, &filepath, &state.monolith_args, state.download_timeout)
+        })
+        .await;
+    if !downloaded {
+        error!("Failed to download webpage: {}", url);
+        // a partial copy would be reused for later links to the same page
+        let _ = fs::remove_file(&filepath);
+    }
+    downloaded
+}
+
+// Downloads the video at `url` to `video_path(url)` with yt-dlp, unless it's
+// there already. Fails for pages without a video.
+async fn download_linked_video(state: &AppState, url: &str) -> bool {
+    let filepath = video_path(url);
+    if !state.refresh_snapshots && PathBuf::from(&filepath).exists() {
+        info!("Reusing video of {}: {}", url, filepath);
+        return true;
+    }
+
+    info!("Downloading video: {}", url);
+    let downloaded = state
+        .retry_policy
+        .run(url, || {
+            download_video(url, &filepath, &state.yt_dlp_format, state.download_timeout)
+        })
+        .await;
+    if !downloaded {
+        let _ = fs::remove_file(&filepath);
+    }
+    downloaded
+}
+
+// Like a local copy of a webpage, but of the audio of the track at `url`.
+async fn download_linked_audio(state: &AppState, key: u64, url: &str) -> bool {
+    let filepath = audio_path(url);
+    if !state.refresh_snapshots && PathBuf::from(&filepath).exists() {
+        info!("Reusing audio of {}: {}", url, filepath);
+        return true;
+    }
+
+    info!("Downloading audio: {}", url);
+    let downloaded = state
+        .retry_policy
+        .run(url, || {
+            download_audio(url, &filepath, AUDIO_FORMAT, state.download_timeout)
+        })
+        .await;
+    if downloaded {
+        return true;
+    }
+
+    error!("Failed to download audio: {}", url);
+    let _ = fs::remove_file(&filepath);
+    let player = audio_player(url);
+    update_linked_note(state, key, |note| {
+        note.content = note.content.replace(&player, "(audio download failed)");
+    })
+    .await;
+    false
+}
+
+// Downloads the file at an `ftp://` or `sftp://` `url` to `attachments/ftp`.
+async fn download_linked_file(state: &AppState, key: u64, url: &str) -> bool {
+    let filepath = ftp_path(url);
+    // the URL may hold a password
+    let logged_url = without_credentials(url);
+    if !state.refresh_snapshots && PathBuf::from(&filepath).exists() {
+        info!("Reusing local copy of {}: {}", logged_url, filepath);
+        return true;
+    }
+
+    info!("Downloading file: {}", logged_url);
+    let downloaded = state
+        .retry_policy
+        .run(&logged_url, || {
+            download_ftp(url, &filepath, state.download_timeout)
+        })
+        .await;
+    if downloaded {
+        return true;
+    }
+
+    error!("Failed to download file: {}", logged_url);
+    let _ = fs::remove_file(&filepath);
+    let local_copy = format!("([local copy](/{}))", filepath);
+    update_linked_note(state, key, |note| {
+        note.content = note.content.replace(&local_copy, "(local copy failed)");
+    })
+    .await;
+    false
+}
+
+// Applies `update` to the note with `key`, then re-renders it and saves all
+// notes.
+async fn update_linked_note(state: &AppState, key: u64, update: impl FnOnce(&mut Note)) {
+    let mut notes = state.notes.write().await;
+    let Some(note) = notes.iter_mut().find(|note| note.key == key) else {
+        // the note was deleted in the meantime
+        return;
+    };
+
+    update(note);
+    note.render(&state.render_config);
+
+    if let Err(e) = state.save_notes(&notes) {
+        error!("Failed to update notes file: {}", e);
+    }
+}
+
+// Status code of a HEAD request to `url` after redirects, or `None` if there
+// was no response.
+async fn head_status(url: &str) -> Option<u16> {
+    let output = Command::new("curl")
+        .args(["--silent", "--head", "--location", "--output", "/dev/null"])
+        .args(["--write-out", "%{http_code}", "--max-time"])
+        .arg(LINK_CHECK_TIMEOUT.as_secs().to_string())
+        .arg(url)
+        .kill_on_drop(true)
+        .output()
+        .await
+        .ok()?;
+
+    // curl writes 000 when there was no response
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .ok()
+        .filter(|status| *status != 0)
+}
+
+// Fetches the page at `url` and reads its OpenGraph title, description and
+// image. Returns `None` when the page can't be fetched or has none of them.
+async fn fetch_preview(url: &str, max_time: Duration) -> Option<LinkPreview> {
+    let output = Command::new("curl")
+        .args(["--silent", "--location", "--max-time"])
+        .arg(max_time.as_secs().to_string())
+        .args(["--max-filesize", "5000000", url])
+        .output()
+        .await
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let page = String::from_utf8_lossy(&output.stdout);
+    let meta_tag = Regex::new(r"(?is)<meta\s[^>]*>").unwrap();
+    let attribute = Regex::new(r#"(?is)([a-z:-]+)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap();
+
+    let mut preview = LinkPreview {
+        url: url.to_string(),
+        title: None,
+        description: None,
+        image: None,
+    };
+
+    for tag in meta_tag.find_iter(&page) {
+        let mut property = None;
+        let mut content = None;
+        for captures in attribute.captures_iter(tag.as_str()) {
+            let value = captures.get(2).or(captures.get(3)).unwrap().as_str();
+            match captures[1].to_ascii_lowercase().as_str() {
+                "property" | "name" => property = Some(value.to_ascii_lowercase()),
+                "content" => content = Some(decode_html_entities(value.trim())),
+                _ => {}
+            }
+        }
+
+        let field = match property.as_deref() {
+            Some("og:title") => &mut preview.title,
+            Some("og:description") => &mut preview.description,
+            Some("og:image") => &mut preview.image,
+            _ => continue,
+        };
+        if field.is_none() {
+            *field = content.filter(|content| !content.is_empty());
+        }
+    }
+
+    if preview.title.is_none() && preview.description.is_none() && preview.image.is_none() {
+        return None;
+    }
+
+    Some(preview)
+}
+
+// POST /admin/rerender
+// Notes files in Markdown or a notes directory don't hold the HTML, so for
+// them re-rendering only updates the in-memory copy. JSON notes files do, so
+// they are saved when a note changed. Either way, running it twice in a row
+// changes nothing.
+async fn rerender_notes(
+    State(state): State<AppState>,
+) -> Result<Json<RerenderResult>, (StatusCode, String)> {
+    let mut notes = state.notes.write().await;
+    let mut changed = 0;
+
+    for note in notes.iter_mut() {
+        let html = render_note(note, &state.render_config);
+        if html != note.html {
+            note.html = html;
+            changed += 1;
+        }
+    }
+
+    if changed > 0 && state.notes_format == NotesFormat::Json {
+        state
+            .save_notes(&notes)
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        state.commit_changes("Re-render notes".to_string());
+    }
+
+    info!("Re-rendered {} notes, {} changed", notes.len(), changed);
+
+    Ok(Json(RerenderResult {
+        total: notes.len(),
+        changed,
+    }))
+}
+
+// POST /admin/renumber?confirm=true
+// Sorts the notes by timestamp and gives them sequential ids again. This
+// changes the ids of existing notes, so links to /notes/:id may point
+// elsewhere afterwards; that's why it does nothing without `confirm=true`.
+async fn renumber_notes(
+    State(state): State<AppState>,
+    Query(query): Query<RenumberQuery>,
+) -> Result<Json<Vec<Note>>, (StatusCode, String)> {
+    if !query.confirm {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "renumbering changes the ids of existing notes and breaks links to them, \
+             pass confirm=true to do it anyway"
+                .to_string(),
+        ));
+    }
+
+    let mut notes = state.notes.write().await;
+    // timestamps that don't parse (e.g. imported in another format) sort first
+    notes.sort_by_cached_key(|note| parse_timestamp(&note.timestamp, &state.timestamp_format));
+    for (index, note) in notes.iter_mut().enumerate() {
+        note.id = state.id_offset + index;
+    }
+
+    state
+        .save_notes(&notes)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    info!("Renumbered {} notes", notes.len());
+    state.commit_changes("Renumber notes".to_string());
+
+    Ok(Json(notes.clone()))
+}
+
+// route POST /upload
+// Every file in the `--upload-field` field (`file` by default) is saved, or
+// every file in the request if there's no field of that name. An optional
+// `note_id` field appends links to the uploaded files to that note and
+// records the association in the attachment index; the response then lists
+// `AttachmentUpload`s instead of just the paths. Files that can't be saved
+// don't stop the others; they are reported with `207 Multi-Status`.
+async fn upload_file(
+    State(state): State<AppState>,
+    mut multipart: Multipart,
+) -> Result<Response, (StatusCode, String)> {
+    let bad_request = |e: MultipartError| (StatusCode::BAD_REQUEST, e.body_text());
+
+    let mut uploads = Vec::new();
+    let mut others = Vec::new();
+    let mut note_id = None;
+
+    while let Some(field) = multipart.next_field().await.map_err(bad_request)? {
+        let field_name = field.name().unwrap_or_default().to_string();
+        if field_name == "note_id" {
+            let text = field.text().await.map_err(bad_request)?;
+            note_id = Some(text.trim().parse::<usize>().map_err(|_| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("invalid note_id {:?}", text.trim()),
+                )
+            })?);
+            continue;
+        }
+
+        let Some(file_name) = field.file_name().map(|name| name.to_string()) else {
+            continue;
+        };
+        let is_image = field
+            .content_type()
+            .is_some_and(|content_type| content_type.starts_with("image/"));
+        let file = (
+            file_name,
+            is_image,
+            field.bytes().await.map_err(bad_request)?,
+        );
+        if field_name == state.upload_field {
+            uploads.push(file);
+        } else {
+            others.push(file);
+        }
+    }
+
+    let files = if uploads.is_empty() { others } else { uploads };
+    if files.is_empty() {
+        error!("Error uploading file");
+        return Err((StatusCode::BAD_REQUEST, "no file in upload".to_string()));
+    }
+
+    if let Some(id) = note_id {
+        let notes = state.notes.read().await;
+        if state.note_index(&notes, id).is_none() {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("request for non-existent note #{id}"),
+            ));
+        }
+    }
+
+    for (name, _, data) in &files {
+        check_upload_type(&state, name, data)?;
+    }
+    let size = files.iter().map(|(_, _, data)| data.len() as u64).sum();
+    if let Some(exceeded) = check_quota(&state, files.len(), size) {
+        warn!("Rejected upload: attachment quota exceeded");
+        return Ok((StatusCode::INSUFFICIENT_STORAGE, Json(exceeded)).into_response());
+    }
+
+    let mut succeeded = Vec::new();
+    let mut failed = Vec::new();
+    for (name, is_image, data) in files {
+        info!("Uploading file: {}", name);
+
+        let path = match save_attachment(&name, &data) {
+            Ok(path) => path,
+            Err(e) => {
+                error!("Failed to save {}: {}", name, e);
+                failed.push(UploadFailure {
+                    name,
+                    error: e.to_string(),
+                });
+                continue;
+            }
+        };
+        let url = format!(
+            "/attachments/{}",
+            path.file_name().unwrap().to_str().unwrap()
+        );
+
+        info!("File saved as {}", path.display());
+
+        match note_id {
+            Some(id) => match attach_to_note(&state, id, &url, is_image).await {
+                Ok(note) => succeeded.push(UploadedFile::Attached(AttachmentUpload { url, note })),
+                Err((_, error)) => failed.push(UploadFailure { name, error }),
+            },
+            None => succeeded.push(UploadedFile::Url(url)),
+        }
+    }
+
+    if failed.is_empty() {
+        return Ok(Json(succeeded).into_response());
+    }
+    if succeeded.is_empty() {
+        let errors = failed
+            .iter()
+            .map(|failure| format!("{}: {}", failure.name, failure.error))
+            .collect::<Vec<_>>();
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, errors.join("\n")));
+    }
+    Ok((
+        StatusCode::MULTI_STATUS,
+        Json(UploadResults { succeeded, failed }),
+    )
+        .into_response())
+}
+
+// route POST /upload/raw
+// Saves an image sent as the request body, as browsers provide pasted images.
+// Pasted images have no name, so they are named with a random UUID.
+async fn upload_raw(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    data: Bytes,
+) -> Result<Response, (StatusCode, String)> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+    let Some(extension) = image_extension(mime) else {
+        return Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!("expected an image, not {content_type:?}"),
+        ));
+    };
+    if sniff::sniff(&data) != Some(mime) {
+        return Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!("the body isn't a valid {mime} image"),
+        ));
+    }
+
+    let name = format!("{}.{extension}", new_request_id());
+
+    check_upload_type(&state, &name, &data)?;
+    if let Some(exceeded) = check_quota(&state, 1, data.len() as u64) {
+        warn!("Rejected upload: attachment quota exceeded");
+        return Ok((StatusCode::INSUFFICIENT_STORAGE, Json(exceeded)).into_response());
+    }
+
+    let path = save_attachment(&name, &data)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    info!("Pasted image saved as {}", path.display());
+
+    Ok(Json(format!(
+        "/attachments/{}",
+        path.file_name().unwrap().to_str().unwrap()
+    ))
+    .into_response())
+}
+
+fn image_extension(mime: &str) -> Option<&'static str> {
+    Some(match mime {
+        "image/png" => "png",
+        "image/jpeg" => "jpg",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/avif" => "avif",
+        "image/bmp" => "bmp",
+        _ => return None,
+    })
+}
+
+// Checks whether saving `new_files` more files of `size` bytes in total would
+// exceed `--max-attachments` or `--max-attachment-storage`. The attachments
+// are counted afresh for that.
+fn check_quota(state: &AppState, new_files: usize, size: u64) -> Option<QuotaExceeded> {
+    if state.max_attachments.is_none() && state.max_attachment_storage.is_none() {
+        return None;
+    }
+
+    let (attachments, files) = state.attachment_usage(Duration::ZERO);
+    let bytes = attachments.values().sum::<u64>();
+    let too_many = state
+        .max_attachments
+        .is_some_and(|max| files + new_files > max);
+    let too_big = state
+        .max_attachment_storage
+        .is_some_and(|max| bytes + size > max);
+    if !too_many && !too_big {
+        return None;
+    }
+
+    Some(QuotaExceeded {
+        error: "quota exceeded",
+        current_files: files,
+        limit_files: state.max_attachments,
+        current_bytes: bytes,
+        limit_bytes: state.max_attachment_storage,
+    })
+}
+
+// Rejects uploads whose content isn't one of `--allowed-types`. The type is
+// detected from the data, as the file name and the content type sent by the
+// client are easily wrong.
+fn check_upload_type(
+    state: &AppState,
+    name: &str,
+    data: &[u8],
+) -> Result<(), (StatusCode, String)> {
+    let detected = sniff::detect(data);
+    if let (Some(sniffed), Some(guessed)) =
+        (sniff::sniff(data), mime_guess::from_path(name).first())
+    {
+        if guessed.essence_str() != sniffed {
+            warn!("{name} looks like {sniffed}, not {guessed} as its extension suggests");
+        }
+    }
+
+    if state.allowed_types.is_empty()
+        || state
+            .allowed_types
+            .iter()
+            .any(|allowed| detected.starts_with(allowed.as_str()))
+    {
+        return Ok(());
+    }
+    Err((
+        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        format!(
+            "{name} is {detected}, only {} files are allowed",
+            state.allowed_types.join(", ")
+        ),
+    ))
+}
+
+// Appends a Markdown link to the attachment at `url` to the note with the
+// given id and records which note the attachment belongs to.
+async fn attach_to_note(
+    state: &AppState,
+    id: usize,
+    url: &str,
+    is_image: bool,
+) -> Result<AttachmentNote, (StatusCode, String)> {
+    let mut notes = state.notes.write().await;
+    let Some(index) = state.note_index(&notes, id) else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("request for non-existent note #{id}"),
+        ));
+    };
+
+    let filename = url.rsplit('/').next().unwrap_or_default();
+    // Link path with spaces needs to be wrapped in < >, same as the editor does
+    let target = if url.contains(' ') {
+        format!("<{url}>")
+    } else {
+        url.to_string()
+    };
+    let link = if is_image {
+        format!("![{filename}]({target})")
+    } else {
+        format!("[{filename}]({target})")
+    };
+
+    let note = &mut notes[index];
+    note.content = format!("{}\n\n{}", note.content, link);
+    note.modified = Some(state.now());
+    note.render(&state.render_config);
+
+    let association = AttachmentNote {
+        note_id: id,
+        note_timestamp: note.timestamp.clone(),
+    };
+
+    state
+        .save_notes(&notes)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    record_attachment_note(filename, &association)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    info!("Attached {} to note {}", url, id);
+    state.commit_changes(format!("Attach {filename} to note {id}"));
+
+    Ok(association)
+}
+
+// The attachment index maps attachment file names to the note they were
+// uploaded for. Callers hold the notes lock, which keeps writes in order.
+fn record_attachment_note(filename: &str, association: &AttachmentNote) -> std::io::Result<()> {
+    let mut index: BTreeMap<String, AttachmentNote> = fs::read_to_string(ATTACHMENT_INDEX)
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default();
+    index.insert(filename.to_string(), association.clone());
+
+    fs::write(ATTACHMENT_INDEX, serde_json::to_string_pretty(&index)?)
+}
+
+fn forget_attachment_note(filename: &str) -> std::io::Result<()> {
+    let Ok(json) = fs::read_to_string(ATTACHMENT_INDEX) else {
+        return Ok(());
+    };
+    let mut index: BTreeMap<String, AttachmentNote> =
+        serde_json::from_str(&json).unwrap_or_default();
+    if index.remove(filename).is_none() {
+        return Ok(());
+    }
+
+    fs::write(ATTACHMENT_INDEX, serde_json::to_string_pretty(&index)?)
+}
+
+// Saves an uploaded file in the attachments directory, picking a free name if
+// one with the same name already exists.
+fn save_attachment(name: &str, data: &[u8]) -> std::io::Result<PathBuf> {
+    // never let a client-supplied name point outside of the attachments directory
+    let name = std::path::Path::new(name)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("upload");
+    let original_path = PathBuf::from("attachments").join(name);
+    let mut counter = 1;
+
+    let original_stem = original_path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("");
+    let original_ext = original_path
+        .extension()
+        .and_then(|s| s.to_str())
+        .unwrap_or("");
+
+    // Generate unique filename if already exists
+    let mut path = original_path.clone();
+    while path.exists() {
+        // e.g: file-1.txt
+        let new_name = if original_ext.is_empty() {
+            format!("{}-{}", original_stem, counter)
+        } else {
+            format!("{}-{}.{}", original_stem, counter, original_ext)
+        };
+
+        path = original_path.parent().unwrap().join(new_name);
+        counter += 1;
+    }
+
+    fs::write(&path, data)?;
+    Ok(path)
+}
+
+// UTILS
+fn local_copy_link(url: &str) -> String {
+    format!("{} ([local copy](/{}))", url, snapshot_path(url))
+}
+
+fn local_file_link(url: &str) -> String {
+    format!("{} ([local copy](/{}))", url, ftp_path(url))
+}
+
+fn local_audio_link(url: &str) -> String {
+    format!("{}\n\n{}", url, audio_player(url))
+}
+
+fn audio_player(url: &str) -> String {
+    format!("<audio controls src=\"/{}\"></audio>", audio_path(url))
+}
+
+fn local_video_link(url: &str) -> String {
+    format!(
+        "{}\n\n<video controls src=\"/{}\"></video>",
+        url,
+        video_path(url)
+    )
+}
+
+fn video_path(url: &str) -> String {
+    let url = url.split('#').next().unwrap_or(url).trim_end_matches('/');
+    format!("attachments/video/{}.mp4", url_to_safe_filename(url))
+}
+
+fn audio_path(url: &str) -> String {
+    let url = url.split('#').next().unwrap_or(url).trim_end_matches('/');
+    format!("attachments/audio/{}.mp3", url_to_safe_filename(url))
+}
+
+fn ftp_path(url: &str) -> String {
+    let url = without_credentials(url);
+    let (_, location) = url.split_once("://").unwrap_or(("", &url));
+    format!("attachments/ftp/{}", url_to_safe_filename(location))
+}
+
+fn snapshot_path(url: &str) -> String {
+    // the fragment and a trailing slash don't change which page is downloaded
+    let url = url.split('#').next().unwrap_or(url).trim_end_matches('/');
+    format!("attachments/webpages/{}.html", url_to_safe_filename(url))
+}
+
+/// Renders a note's content followed by cards for its link previews.
+fn render_note(note: &Note, config: &RenderConfig) -> String {
+    static HEADING_ID: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r#"<h[1-6] id="([^"]*)">"#).unwrap());
+
+    // all notes are on one page, so heading and footnote ids like `intro` or
+    // `fn-1` must not repeat
+    let note_prefix = format!("note{}-", note.id);
+    let note_config = RenderConfig {
+        heading_id_prefix: format!("{}{note_prefix}", config.heading_id_prefix),
+        ..config.clone()
+    };
+    let mut html = md_to_html(&note.content, &note_config)
+        .replace("id=\"fn-", &format!("id=\"{note_prefix}fn-"))
+        .replace("href=\"#fn-", &format!("href=\"#{note_prefix}fn-"))
+        .replace("id=\"fnref-", &format!("id=\"{note_prefix}fnref-"))
+        .replace("href=\"#fnref-", &format!("href=\"#{note_prefix}fnref-"));
+
+    // links within the note to its headings, written without the note prefix
+    let heading_ids = HEADING_ID
+        .captures_iter(&html)
+        .map(|caps| caps[1].to_string())
+        .collect::<Vec<_>>();
+    for id in heading_ids {
+        if let Some(rest) = id.strip_prefix(&note_config.heading_id_prefix) {
+            let written = format!("href=\"#{}{rest}\"", config.heading_id_prefix);
+            html = html.replace(&written, &format!("href=\"#{id}\""));
+        }
+    }
+
+    for preview in &note.previews {
+        html.push_str(&format!(
+            "<div class=\"linkPreview\"><a href=\"{}\">",
+            escape_html(&preview.url)
+        ));
+        if let Some(image) = &preview.image {
+            html.push_str(&format!("<img src=\"{}\" alt=\"\">", escape_html(image)));
+        }
+        html.push_str(&format!(
+            "<strong>{}</strong></a>",
+            escape_html(preview.title.as_deref().unwrap_or(&preview.url))
+        ));
+        if let Some(description) = &preview.description {
+            html.push_str(&format!("<p>{}</p>", escape_html(description)));
+        }
+        html.push_str("</div>\n");
+    }
+
+    html
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn decode_html_entities(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&amp;", "&")
+}
+
+// Writing no notes over a file that has some is almost always a bug (e.g. the
+// notes failed to load), so it is refused unless `allow_empty` is set.
+fn write_notes_to_file(
+    file: &PathBuf,
+    format: NotesFormat,
+    notes: &[Note],
+    allow_empty: bool,
+) -> std::io::Result<()> {
+    if format == NotesFormat::Obsidian {
+        return Err(std::io::Error::other("the Obsidian vault is read-only"));
+    }
+    let has_notes = match format {
+        NotesFormat::Dir => notes_dir::has_notes(file),
+        _ => fs::read_to_string(file).is_ok_and(|content| !matches!(content.trim(), "" | "[]")),
+    };
+    if notes.is_empty() && !allow_empty && has_notes {
+        error!(
+            "Refusing to overwrite {} with no notes; start with --allow-empty-overwrite if this is intended",
+            file.display()
+        );
+        return Err(std::io::Error::other(
+            "refusing to remove every note from the notes file (see --allow-empty-overwrite)",
+        ));
+    }
+
+    if format == NotesFormat::Dir {
+        return notes_dir::save(file, notes);
+    }
+    fs::write(file, notes_file_content(format, notes)?)
+}
+
+// What a notes file with `notes` contains. A notes directory is turned into
+// a single Markdown file, e.g. for backups.
+fn notes_file_content(format: NotesFormat, notes: &[Note]) -> std::io::Result<String> {
+    Ok(match format {
+        NotesFormat::Markdown | NotesFormat::Dir | NotesFormat::Obsidian => {
+            notes.iter().map(note_block).collect::<String>()
+        }
+        NotesFormat::Json => serde_json::to_string_pretty(notes)?,
+    })
+}
+
+// Writes a backup right away and then every `interval`.
+fn spawn_backups(state: AppState, interval: Duration) {
+    spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            if let Err(e) = backup_notes(&state).await {
+                error!("Failed to back up notes: {e}");
+            }
+        }
+    });
+}
+
+// Backups are made from the notes in memory, so they are consistent even if
+// the notes file is being written. Only the newest `BACKUPS_KEPT` are kept.
+async fn backup_notes(state: &AppState) -> std::io::Result<()> {
+    let Some(dir) = &state.backup_dir else {
+        return Ok(());
+    };
+    fs::create_dir_all(dir)?;
+
+    let extension = match state.notes_format {
+        NotesFormat::Markdown | NotesFormat::Dir | NotesFormat::Obsidian => "md",
+        NotesFormat::Json => "json",
+    };
+    let hour = format_timestamp(Utc::now(), state.timezone, "%Y-%m-%d-%H");
+    let name = format!("notes-{hour}.{extension}");
+    {
+        let notes = state.notes.read().await;
+        fs::write(
+            dir.join(&name),
+            notes_file_content(state.notes_format, &notes)?,
+        )?;
+    }
+    info!("Backed up notes to {}", dir.join(&name).display());
+
+    // the names sort chronologically
+    let backups = backup_files(dir)?;
+    for old in backups.iter().rev().skip(BACKUPS_KEPT) {
+        fs::remove_file(dir.join(old))?;
+    }
+    Ok(())
+}
+
+// Names of the backups in `dir`, oldest first.
+fn backup_files(dir: &std::path::Path) -> std::io::Result<Vec<String>> {
+    let mut names = fs::read_dir(dir)?
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|name| {
+            name.starts_with("notes-") && (name.ends_with(".md") || name.ends_with(".json"))
+        })
+        .collect::<Vec<_>>();
+    names.sort();
+    Ok(names)
+}
+
+// Polls the notes file for `--watch`, as there is no portable way to be told
+// about changes. The file's size is checked too, as some file systems only
+// store modification times in seconds.
+fn watch_notes_file(state: AppState) {
+    let format = state.notes_format;
+    let file_version = move |file: &PathBuf| match format {
+        NotesFormat::Dir => notes_dir::version(file),
+        NotesFormat::Obsidian => obsidian::version(file),
+        _ => fs::metadata(file)
+            .ok()
+            .map(|metadata| (metadata.modified().ok(), metadata.len())),
+    };
+
+    spawn(async move {
+        let mut last = file_version(&state.notes_file);
+        loop {
+            sleep(WATCH_INTERVAL).await;
+            let current = file_version(&state.notes_file);
+            if current == last {
+                continue;
+            }
+            sleep(WATCH_DEBOUNCE).await;
+            if file_version(&state.notes_file) != current {
+                // still being written, try again next time
+                continue;
+            }
+            last = current;
+            reload_notes(&state).await;
+        }
+    });
+}
+
+// Replaces the notes with those in the notes file, unless the file just
+// contains what textpod wrote itself.
+async fn reload_notes(state: &AppState) {
+    let mut notes = state.notes.write().await;
+    if !matches!(state.notes_format, NotesFormat::Dir | NotesFormat::Obsidian) {
+        let Ok(content) = fs::read_to_string(&state.notes_file) else {
+            // deleted or being replaced; keep the notes until it's back
+            return;
+        };
+        if notes_file_content(state.notes_format, &notes).is_ok_and(|current| current == content) {
+            return;
+        }
+        if state.notes_format == NotesFormat::Json
+            && serde_json::from_str::<Vec<Note>>(&content).is_err()
+        {
+            warn!(
+                "Not reloading {}, it isn't valid JSON",
+                state.notes_file.display()
+            );
+            return;
+        }
+    }
+
+    let mut reloaded = load_notes(
+        &state.notes_file,
+        state.notes_format,
+        state.id_offset,
+        &state.render_config,
+        &state.timestamp_format,
+        state.timezone,
+    );
+    // so downloads still running find notes that weren't changed
+    let mut unchanged = notes.iter().collect::<Vec<_>>();
+    for note in &mut reloaded {
+        if let Some(index) = unchanged.iter().position(|current| {
+            current.timestamp == note.timestamp && current.content == note.content
+        }) {
+            note.key = unchanged.remove(index).key;
+        }
+    }
+    // the files of a notes directory can't be compared as a whole
+    if reloaded
+        .iter()
+        .map(note_block)
+        .eq(notes.iter().map(note_block))
+    {
+        return;
+    }
+    *notes = reloaded;
+    info!(
+        "Reloaded {} notes from {}",
+        notes.len(),
+        state.notes_file.display()
+    );
+}
+
+// Tells a field that is present but `null` (`Some(None)`) apart from a
+// missing one (`None`, via `#[serde(default)]`).
+fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+// A cursor identifies the last note of a page by its id, timestamp and a hash
+// of its content, so the note can be found again after deletions have shifted
+// the ids (several notes often share a timestamp).
+fn note_cursor(note: &Note) -> String {
+    URL_SAFE_NO_PAD.encode(format!(
+        "{}|{:x}|{}",
+        note.id,
+        content_hash(&note.content),
+        note.timestamp
+    ))
+}
+
+/// Position of the first note after the one `cursor` points to.
+fn cursor_position(notes: &[Note], cursor: &str) -> Option<usize> {
+    let decoded = String::from_utf8(URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()?;
+    let mut parts = decoded.splitn(3, '|');
+    let id = parts.next()?.parse::<usize>().ok()?;
+    let hash = u64::from_str_radix(parts.next()?, 16).ok()?;
+    let timestamp = parts.next()?;
+
+    let is_last_seen =
+        |note: &Note| note.timestamp == timestamp && content_hash(&note.content) == hash;
+    // ids only ever move down, so the note still has its id or a lower one
+    let last_seen = notes
+        .iter()
+        .position(|note| note.id == id && is_last_seen(note))
+        .or_else(|| {
+            notes
+                .iter()
+                .rposition(|note| note.id < id && is_last_seen(note))
+        });
+
+    Some(match last_seen {
+        Some(position) => position + 1,
+        // the note itself is gone, continue with whatever came after it
+        None => notes
+            .iter()
+            .position(|note| note.timestamp.as_str() > timestamp)
+            .unwrap_or(notes.len()),
+    })
+}
+
+fn content_hash(content: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Also returns the number of files, not counting hidden ones like the
+// attachment index.
+fn attachments_usage(dir: &PathBuf) -> (AttachmentsUsage, usize) {
+    let mut usage = BTreeMap::new();
+    let mut files = 0;
+    let Ok(entries) = fs::read_dir(dir) else {
+        return (usage, files);
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let (bytes, dir_files) = dir_usage(&path);
+            *usage.entry(name).or_insert(0) += bytes;
+            files += dir_files;
+        } else if let Ok(metadata) = entry.metadata() {
+            *usage.entry("uploads".to_string()).or_insert(0) += metadata.len();
+            if !entry.file_name().to_string_lossy().starts_with('.') {
+                files += 1;
+            }
+        }
+    }
+
+    (usage, files)
+}
+
+// Bytes and number of (non-hidden) files in `dir` and its subdirectories.
+fn dir_usage(dir: &PathBuf) -> (u64, usize) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return (0, 0);
+    };
+
+    entries
+        .flatten()
+        .map(|entry| {
+            let path = entry.path();
+            if path.is_dir() {
+                dir_usage(&path)
+            } else {
+                let hidden = entry.file_name().to_string_lossy().starts_with('.');
+                let bytes = entry.metadata().map(|m| m.len()).unwrap_or(0);
+                (bytes, usize::from(!hidden))
+            }
+        })
+        .fold((0, 0), |(bytes, files), (b, f)| (bytes + b, files + f))
+}
+
+fn url_to_safe_filename(url: &str) -> String {
+    let mut safe_name = String::with_capacity(url.len());
+
+    let stripped_url = url
+        .trim()
+        .strip_prefix("http://")
+        .unwrap_or(url)
+        .strip_prefix("https://")
+        .unwrap_or(url);
+
+    for c in stripped_url.chars() {
+        match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => safe_name.push('_'),
+            c if c.is_alphanumeric() || c == '-' || c == '.' || c == '_' => safe_name.push(c),
+            _ => safe_name.push('_'),
+        }
+    }
+
+    safe_name.trim_matches(|c| c == '.' || c == ' ').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+    // A directory of its own for one test, removed afterwards.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!("textpod-{}-{name}", process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // The state of a server started with `args`, keeping its notes in `dir`.
+    fn state(dir: &TempDir, args: &[&str]) -> AppState {
+        let file = dir.0.join("notes");
+        let file_arg = ["--notes-file", file.to_str().unwrap()];
+        let args = Args::parse_from(["textpod"].iter().chain(&file_arg).chain(args));
+        let notes = load_notes(
+            &file,
+            args.format,
+            0,
+            &RenderConfig::default(),
+            FORMAT,
+            None,
+        );
+        AppState::new(
+            &args,
+            notes,
+            file,
+            args.format,
+            args.read_only,
+            RenderConfig::default(),
+        )
+    }
+
+    fn note(id: usize, timestamp: &str, content: &str) -> Note {
+        Note::new(id, timestamp.to_string(), content.to_string())
+    }
+
+    #[test]
+    fn empty_write_does_not_wipe_notes_file() {
+        let dir = TempDir::new("empty-write");
+        for format in [NotesFormat::Markdown, NotesFormat::Json, NotesFormat::Dir] {
+            let file = dir.0.join(format!("notes-{}", format as u8));
+            let notes = [note(0, "2024-01-01 12:00:00", "keep me")];
+            write_notes_to_file(&file, format, &notes, false).unwrap();
+
+            assert!(write_notes_to_file(&file, format, &[], false).is_err());
+            let loaded = load_notes(&file, format, 0, &RenderConfig::default(), FORMAT, None);
+            assert_eq!(loaded.len(), 1);
+            assert_eq!(loaded[0].content, "keep me");
+        }
+    }
+
+    #[test]
+    fn empty_write_allowed_when_asked_for() {
+        let dir = TempDir::new("empty-write-allowed");
+        let file = dir.0.join("notes.md");
+        let notes = [note(0, "2024-01-01 12:00:00", "delete me")];
+        write_notes_to_file(&file, NotesFormat::Markdown, &notes, false).unwrap();
+
+        write_notes_to_file(&file, NotesFormat::Markdown, &[], true).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "");
+    }
+
+    #[test]
+    fn heading_ids_are_unique_across_notes() {
+        let config = RenderConfig::default();
+        let first = render_note(&note(0, "2024-01-01 12:00:00", "## Intro"), &config);
+        let second = render_note(
+            &note(1, "2024-01-01 12:00:00", "## Intro\n\nSee [above](#intro)."),
+            &config,
+        );
+        assert!(first.contains("<h2 id=\"note0-intro\">"));
+        assert!(second.contains("<h2 id=\"note1-intro\">"));
+        // links within a note still reach its own heading
+        assert!(second.contains("<a href=\"#note1-intro\">above</a>"));
+    }
+
+    #[test]
+    fn taken_and_invalid_slugs_are_replaced() {
+        let mut notes = vec![
+            note(0, "2024-01-01 12:00:00", "# Reading list"),
+            note(1, "2024-01-02 12:00:00", "# Reading list"),
+            note(2, "2024-01-03 12:00:00", "Books"),
+            note(3, "2024-01-04 12:00:00", "Films"),
+        ];
+        notes[0].slug = Some("reading-list".to_string());
+        notes[1].slug = Some("reading-list".to_string());
+        notes[2].slug = Some("Not Valid".to_string());
+        assign_slugs(&mut notes);
+
+        let slugs = notes
+            .iter()
+            .map(|note| note.slug.as_deref().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(slugs, ["reading-list", "reading-list-2", "books", "films"]);
+    }
+
+    #[test]
+    fn parses_iso_8601_timestamps() {
+        let content =
+            "2024-03-01T09:30:00+01:00\nfirst\n\n---\n\n2024-03-02T18:00:00-05:00\nsecond\n";
+        let notes = parse_note_blocks(content, "%Y-%m-%dT%H:%M:%S%:z", "fallback");
+
+        let parsed = notes
+            .iter()
+            .map(|note| (note.id, note.timestamp.as_str(), note.content.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            parsed,
+            [
+                (0, "2024-03-01T09:30:00+01:00", "first"),
+                (1, "2024-03-02T18:00:00-05:00", "second"),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_custom_format_timestamps() {
+        let format = "%d/%m/%Y %H:%M";
+        let content = "31/12/2023 23:59\nold year\n\n---\n\n01/01/2024 00:01\nnew year\n";
+        let notes = parse_note_blocks(content, format, "fallback");
+
+        assert_eq!(notes.len(), 2);
+        assert_eq!(notes[0].timestamp, "31/12/2023 23:59");
+        assert_eq!(notes[0].content, "old year");
+        assert_eq!(notes[1].timestamp, "01/01/2024 00:01");
+        assert_eq!(notes[1].content, "new year");
+        assert!(
+            parse_timestamp(&notes[0].timestamp, format)
+                < parse_timestamp(&notes[1].timestamp, format)
+        );
+    }
+
+    #[test]
+    fn block_without_timestamp_is_content() {
+        let content = "2024-01-01 12:00:00\ndated\n\n---\n\nJust some text\nover two lines\n";
+        let notes = parse_note_blocks(content, FORMAT, "2024-06-01 08:00:00");
+
+        assert_eq!(notes[0].timestamp, "2024-01-01 12:00:00");
+        assert_eq!(notes[1].timestamp, "2024-06-01 08:00:00");
+        assert_eq!(notes[1].content, "Just some text\nover two lines");
+    }
+
+    #[test]
+    fn parses_auto_tag_rules() {
+        let rule = parse_auto_tag(r"(?i)\btodo\b=todo").unwrap();
+        assert_eq!(rule.tag, "todo");
+        assert!(rule.pattern.is_match("a TODO item"));
+
+        // the regex may contain `=`, the tag can't
+        let rule = parse_auto_tag("a=b=c").unwrap();
+        assert_eq!(rule.pattern.as_str(), "a=b");
+        assert_eq!(rule.tag, "c");
+    }
+
+    #[test]
+    fn rejects_malformed_auto_tag_rules() {
+        assert!(parse_auto_tag("todo").is_err());
+        assert!(parse_auto_tag("todo=").is_err());
+        assert!(parse_auto_tag("todo=two words").is_err());
+        assert!(parse_auto_tag("(unclosed=todo").is_err());
+        assert!(Args::try_parse_from(["textpod", "--auto-tag", "(unclosed=todo"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn auto_tags_are_applied_on_create() {
+        let dir = TempDir::new("auto-tags");
+        let state = state(
+            &dir,
+            &[
+                "--auto-tag",
+                "https?://=link",
+                "--auto-tag",
+                r"(?i)\btodo\b=todo",
+            ],
+        );
+
+        let note = create_note(
+            &state,
+            "TODO read https://example.com",
+            vec!["todo".to_string(), "rust".to_string()],
+        )
+        .await
+        .unwrap();
+        assert_eq!(note.tags, ["todo", "rust", "link"]);
+        let note = create_note(&state, "nothing to match", Vec::new())
+            .await
+            .unwrap();
+        assert!(note.tags.is_empty());
+
+        // saved with the note
+        let saved = fs::read_to_string(&state.notes_file).unwrap();
+        assert!(saved.contains("tags: todo rust link\n"));
+    }
+
+    #[tokio::test]
+    async fn notes_are_in_id_order_in_every_format() {
+        // more than ten, so file names of a notes directory sort differently
+        // than ids, and timestamps out of order
+        let notes = (0..12)
+            .map(|id| {
+                let day = 1 + (id * 7) % 12;
+                note(
+                    id,
+                    &format!("2024-01-{day:02} 12:00:00"),
+                    &format!("note {id}"),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let mut listed = Vec::new();
+        for format in ["markdown", "json", "dir"] {
+            let dir = TempDir::new(&format!("order-{format}"));
+            let state = state(&dir, &["--format", format]);
+            write_notes_to_file(&state.notes_file, state.notes_format, &notes, false).unwrap();
+            reload_notes(&state).await;
+
+            let query = Query::try_from_uri(&"/notes".parse().unwrap()).unwrap();
+            let response = get_notes(State(state), query).await.unwrap();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let ids = serde_json::from_slice::<Vec<Note>>(&body)
+                .unwrap()
+                .iter()
+                .map(|note| (note.id, note.content.clone()))
+                .collect::<Vec<_>>();
+            listed.push(ids);
+        }
+
+        let expected = (0..12)
+            .map(|id| (id, format!("note {id}")))
+            .collect::<Vec<_>>();
+        for ids in listed {
+            assert_eq!(ids, expected);
+        }
+    }
+
+    fn content_type(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[tokio::test]
+    async fn put_note_with_json_body() {
+        let dir = TempDir::new("put-json");
+        let state = state(&dir, &[]);
+        create_note(&state, "old", Vec::new()).await.unwrap();
+
+        let body = Bytes::from(r#"{"content": "new"}"#);
+        let Json(note) = update_note(
+            State(state.clone()),
+            Path(0),
+            content_type("application/json"),
+            body,
+        )
+        .await
+        .unwrap();
+        assert_eq!(note.content, "new");
+        assert_eq!(state.notes.read().await[0].content, "new");
+
+        let body = Bytes::from("not json");
+        let (status, _) = update_note(
+            State(state),
+            Path(0),
+            content_type("application/json"),
+            body,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn put_note_with_text_body() {
+        let dir = TempDir::new("put-text");
+        let state = state(&dir, &[]);
+        create_note(&state, "old", Vec::new()).await.unwrap();
+
+        for (media_type, content) in [
+            (
+                "text/plain; charset=utf-8",
+                "# Plain\n\n{\"not\": \"json\"}",
+            ),
+            ("text/markdown", "# Markdown"),
+        ] {
+            let Json(note) = update_note(
+                State(state.clone()),
+                Path(0),
+                content_type(media_type),
+                Bytes::from(content),
+            )
+            .await
+            .unwrap();
+            assert_eq!(note.content, content);
+        }
+
+        let (status, _) = update_note(
+            State(state),
+            Path(0),
+            content_type("application/octet-stream"),
+            Bytes::from("new"),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[test]
+    fn content_like_metadata_survives_saving() {
+        let contents = [
+            "modified: 2024-01-02 12:00:00",
+            "priority: 3",
+            "classes: wide",
+            "tags: todo",
+            "slug: my-note",
+            "pinned: true",
+            "archived: true",
+            "checksum: 0123abcd",
+            r#"preview: {"url": "https://example.com"}"#,
+            "\\priority: 3",
+            "\\just a backslash",
+            "priority: 3\ntags: todo\n\nand more",
+        ];
+        for content in contents {
+            let mut saved = note(0, "2024-01-01 12:00:00", content);
+            saved.tags = vec!["kept".to_string()];
+            let block = note_block(&saved);
+            let loaded = parse_note_blocks(&block, FORMAT, "fallback");
+
+            assert_eq!(loaded.len(), 1);
+            assert_eq!(loaded[0].content, content);
+            assert_eq!(loaded[0].tags, ["kept"]);
+            assert_eq!(loaded[0].modified, None);
+            assert_eq!(loaded[0].priority, None);
+            assert_eq!(loaded[0].slug, None);
+            assert!(loaded[0].classes.is_empty());
+            assert!(!loaded[0].pinned && !loaded[0].archived);
+            assert!(loaded[0].previews.is_empty());
+            assert_eq!(loaded[0].checksum, Some(content_checksum(content)));
+        }
+    }
+
+    #[tokio::test]
+    async fn verify_notices_damaged_notes_file() {
+        for format in ["markdown", "json"] {
+            let dir = TempDir::new(&format!("verify-{format}"));
+            let state = state(&dir, &["--format", format]);
+            create_note(&state, "first", Vec::new()).await.unwrap();
+            create_note(&state, "second", Vec::new()).await.unwrap();
+
+            let Json(verification) = verify_note(State(state.clone()), Path(1)).await.unwrap();
+            assert!(verification.valid);
+            assert_eq!(verification.actual, Some(content_checksum("second")));
+            assert_eq!(verification.stored, verification.actual);
+
+            // changed behind textpod's back
+            let saved = fs::read_to_string(&state.notes_file).unwrap();
+            fs::write(&state.notes_file, saved.replace("second", "sec0nd")).unwrap();
+            let Json(verification) = verify_note(State(state.clone()), Path(1)).await.unwrap();
+            assert!(!verification.valid);
+            assert_eq!(verification.actual, Some(content_checksum("sec0nd")));
+
+            // cut off, so no longer valid JSON
+            fs::write(&state.notes_file, "[{\"timestamp\": ").unwrap();
+            let Json(verification) = verify_note(State(state.clone()), Path(0)).await.unwrap();
+            assert!(!verification.valid);
+            if format == "json" {
+                assert_eq!(verification.actual, None);
+                assert_eq!(verification.stored, None);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn downloads_stay_with_their_note() {
+        let dir = TempDir::new("download-tracker");
+        let state = state(&dir, &[]);
+        let first = create_note(&state, "first", Vec::new()).await.unwrap();
+        let second = create_note(&state, "second", Vec::new()).await.unwrap();
+        // created in the same second
+        state.notes.write().await[1].timestamp = first.timestamp.clone();
+        state
+            .download_tracker
+            .queue(first.key, "https://example.com/a");
+        state
+            .download_tracker
+            .queue(second.key, "https://example.com/b");
+
+        let urls = |downloads: Vec<DownloadStatus>| {
+            downloads
+                .into_iter()
+                .map(|download| download.url)
+                .collect::<Vec<_>>()
+        };
+        let Json(downloads) = get_note_downloads(State(state.clone()), Path(1))
+            .await
+            .unwrap();
+        assert_eq!(urls(downloads), ["https://example.com/b"]);
+        let Json(response) = get_downloads(State(state.clone())).await;
+        let mut downloads = response
+            .downloads
+            .into_iter()
+            .map(|download| (download.note_id, download.download.url))
+            .collect::<Vec<_>>();
+        downloads.sort();
+        assert_eq!(
+            downloads,
+            [
+                (0, "https://example.com/a".to_string()),
+                (1, "https://example.com/b".to_string())
+            ]
+        );
+
+        // ids shift, the downloads follow the note
+        delete_note_by_id(State(state.clone()), Path(0))
+            .await
+            .unwrap();
+        let Json(downloads) = get_note_downloads(State(state.clone()), Path(0))
+            .await
+            .unwrap();
+        assert_eq!(urls(downloads), ["https://example.com/b"]);
+    }
+
+    #[tokio::test]
+    async fn batch_notes_linking_the_same_url_are_updated_separately() {
+        let dir = TempDir::new("batch-links");
+        let state = state(&dir, &[]);
+        let create = |content: &str| BatchOp::Create {
+            content: content.to_string(),
+        };
+        // the same timestamp and link, as with `+https://example.com` links
+        let Json(results) = apply_batch(
+            State(state.clone()),
+            Json(vec![
+                create("read https://example.com"),
+                create("read https://example.com"),
+            ]),
+        )
+        .await
+        .unwrap();
+        let keys = results
+            .iter()
+            .map(|result| result.note.as_ref().unwrap().key)
+            .collect::<Vec<_>>();
+        assert_ne!(keys[0], keys[1]);
+
+        // what the download of the first note's link does once it's done
+        update_linked_note(&state, keys[0], |note| {
+            note.content = note
+                .content
+                .replace("https://example.com", "(local copy failed)")
+        })
+        .await;
+        let notes = state.notes.read().await;
+        assert_eq!(notes[0].content, "read (local copy failed)");
+        assert_eq!(notes[1].content, "read https://example.com");
+    }
+
+    #[test]
+    fn links_are_prepared_for_their_link_mode() {
+        let dir = TempDir::new("link-modes");
+        let page = "https://example.com/page";
+        let audio = "https://soundcloud.com/artist/track";
+        for mode in ["snapshot", "preview", "auto", "video"] {
+            let state = state(&dir, &["--link-mode", mode]);
+            let (content, links) = prepare_content(&state, &format!("+{page} +{audio}"));
+
+            assert_eq!(links, [format!("+{page}"), format!("+{audio}")]);
+            // audio gets its player in every mode
+            assert!(content.contains(&local_audio_link(audio)), "{mode}");
+            // the others only link to what was saved once it's there
+            let page_link = if mode == "snapshot" {
+                local_copy_link(page)
+            } else {
+                format!("{page} ")
+            };
+            assert!(content.starts_with(&page_link), "{mode}");
+        }
+    }
+
+    #[test]
+    fn yt_dlp_formats_are_checked() {
+        let args = Args::parse_from(["textpod"]);
+        assert_eq!(args.yt_dlp_format, "bestvideo+bestaudio/best");
+        assert!(parse_yt_dlp_format("bestvideo[height<=?1080]+bestaudio/best").is_ok());
+        for format in ["", "best; rm -rf ~", "best && true", "'best'", "best video"] {
+            assert!(parse_yt_dlp_format(format).is_err(), "{format}");
+        }
+    }
+
+    #[tokio::test]
+    async fn pinned_notes_are_listed_first_by_default() {
+        let dir = TempDir::new("pinned-first");
+        let state = state(&dir, &[]);
+        for content in ["a", "b", "c", "d"] {
+            create_note(&state, content, Vec::new()).await.unwrap();
+        }
+        let _ = pin_note(State(state.clone()), Path(3)).await.unwrap();
+        let _ = pin_note(State(state.clone()), Path(1)).await.unwrap();
+
+        let listed = |uri: &str| {
+            let state = state.clone();
+            let query = Query::try_from_uri(&uri.parse().unwrap()).unwrap();
+            async move {
+                let response = get_notes(State(state), query).await.unwrap();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                serde_json::from_slice::<Vec<Note>>(&body)
+                    .unwrap()
+                    .iter()
+                    .map(|note| note.id)
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(listed("/notes").await, [1, 3, 0, 2]);
+        assert_eq!(listed("/notes?sort=created_asc").await, [0, 1, 2, 3]);
+        assert_eq!(listed("/notes?sort=pinned").await, [1, 3, 2, 0]);
+    }
+
+    #[tokio::test]
+    async fn rerendering_saves_json_notes() {
+        let dir = TempDir::new("rerender");
+        let state = state(&dir, &["--format", "json"]);
+        create_note(&state, "*new*", Vec::new()).await.unwrap();
+        {
+            // as rendered with other settings
+            let mut notes = state.notes.write().await;
+            notes[0].html = "<p>old</p>".to_string();
+            state.save_notes(&notes).unwrap();
+        }
+
+        let Json(result) = rerender_notes(State(state.clone())).await.unwrap();
+        assert_eq!(result.changed, 1);
+        let saved = fs::read_to_string(&state.notes_file).unwrap();
+        let saved = serde_json::from_str::<Vec<Note>>(&saved).unwrap();
+        assert_eq!(saved[0].html, state.notes.read().await[0].html);
+        assert!(saved[0].html.contains("<em>new</em>"));
+
+        let Json(result) = rerender_notes(State(state.clone())).await.unwrap();
+        assert_eq!(result.changed, 0);
+    }
+
+    #[test]
+    fn pasted_image_names_are_v4_uuids() {
+        let uuid =
+            Regex::new("^[0-9a-f]{8}-[0-9a-f]{4}-4[0-9a-f]{3}-[89ab][0-9a-f]{3}-[0-9a-f]{12}$")
+                .unwrap();
+        let ids = (0..1000)
+            .map(|_| new_request_id())
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(ids.len(), 1000);
+        assert!(ids.iter().all(|id| uuid.is_match(id)));
+    }
+
+    #[tokio::test]
+    async fn batches_are_rate_limited_and_read_only() {
+        use tower::ServiceExt;
+
+        let dir = TempDir::new("batch-guards");
+        let router = |state: AppState| {
+            Router::new()
+                .route("/batch", post(apply_batch))
+                .route("/notes/batch", post(get_notes_by_ids))
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    check_rate_limit,
+                ))
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    check_read_only,
+                ))
+                .with_state(state)
+        };
+        let request = |path: &str, body: &str| {
+            let mut request = Request::post(path)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            request
+                .extensions_mut()
+                .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 1234))));
+            request
+        };
+        let creates = |n: usize| {
+            let ops = vec![r#"{"op": "create", "content": "new"}"#; n];
+            format!("[{}]", ops.join(", "))
+        };
+
+        let app = router(state(&dir, &["--rate-limit-notes", "3"]));
+        let status = |body: String| {
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request("/batch", &body)).await.unwrap();
+                response.status()
+            }
+        };
+        assert_eq!(status(creates(2)).await, StatusCode::OK);
+        // one note left this minute
+        assert_eq!(status(creates(2)).await, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            status(r#"[{"op": "delete", "id": 0}]"#.to_string()).await,
+            StatusCode::OK
+        );
+        assert_eq!(status(creates(1)).await, StatusCode::OK);
+        assert_eq!(status(creates(1)).await, StatusCode::TOO_MANY_REQUESTS);
+
+        let app = router(state(&dir, &["--read-only"]));
+        let response = app
+            .clone()
+            .oneshot(request("/batch", &creates(1)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        let response = app.oneshot(request("/notes/batch", "[0]")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn audio_hosts_are_host_names() {
+        let args = Args::parse_from(["textpod", "--audio-host", "Podbean.com."]);
+        assert_eq!(args.audio_hosts, ["podbean.com"]);
+        for host in [
+            "",
+            "https://podbean.com",
+            "podbean.com/e",
+            "podbean.com:443",
+        ] {
+            assert!(Args::try_parse_from(["textpod", "--audio-host", host]).is_err());
+        }
+    }
 }
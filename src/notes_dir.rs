@@ -0,0 +1,91 @@
+// Storage for `--format dir`: a directory with one `<id>.md` file per note,
+// each holding the note in the format of `note_block`.
+
+use crate::{format_timestamp, note_block, parse_note_blocks, Note};
+use chrono::{DateTime, FixedOffset, Utc};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Reads the notes in `dir` in the order of their file names. Ids are
+/// positions and the HTML is left empty, as with `parse_note_blocks`.
+pub fn load(dir: &Path, timestamp_format: &str, timezone: Option<FixedOffset>) -> Vec<Note> {
+    let Ok(files) = note_files(dir) else {
+        return Vec::new();
+    };
+
+    let mut notes = Vec::new();
+    for (_, path) in files {
+        let Ok(content) = fs::read_to_string(&path) else {
+            continue;
+        };
+        let modified = fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .map(DateTime::<Utc>::from)
+            .unwrap_or_else(|_| Utc::now());
+        let modified = format_timestamp(modified, timezone, timestamp_format);
+        notes.extend(parse_note_blocks(&content, timestamp_format, &modified));
+    }
+    for (index, note) in notes.iter_mut().enumerate() {
+        note.id = index;
+    }
+    notes
+}
+
+/// Writes every note to its own file, skipping files that are already up to
+/// date so unchanged notes keep their modification time, and removes the
+/// files of notes that no longer exist.
+pub fn save(dir: &Path, notes: &[Note]) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+
+    for note in notes {
+        let path = dir.join(format!("{}.md", note.id));
+        let block = note_block(note);
+        let content = block.strip_suffix("\n---\n\n").unwrap_or(&block);
+        if fs::read_to_string(&path).ok().as_deref() != Some(content) {
+            fs::write(&path, content)?;
+        }
+    }
+
+    for (id, path) in note_files(dir)? {
+        if !notes.iter().any(|note| note.id == id) {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn has_notes(dir: &Path) -> bool {
+    note_files(dir).is_ok_and(|files| !files.is_empty())
+}
+
+/// Latest modification time and total size of the note files, to notice
+/// changes made by other programs.
+pub fn version(dir: &Path) -> Option<(Option<SystemTime>, u64)> {
+    let files = note_files(dir).ok()?;
+    let mut latest = fs::metadata(dir).and_then(|m| m.modified()).ok();
+    let mut size = 0;
+    for (_, path) in files {
+        if let Ok(metadata) = fs::metadata(path) {
+            latest = latest.max(metadata.modified().ok());
+            size += metadata.len();
+        }
+    }
+    Some((latest, size))
+}
+
+// Files named `<number>.md`, by number.
+fn note_files(dir: &Path) -> io::Result<Vec<(usize, PathBuf)>> {
+    let mut files = fs::read_dir(dir)?
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name();
+            let id = name.to_str()?.strip_suffix(".md")?.parse().ok()?;
+            Some((id, entry.path()))
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+    Ok(files)
+}
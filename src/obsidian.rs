@@ -0,0 +1,87 @@
+// Reading an Obsidian vault for `--obsidian-vault`: every Markdown file in
+// the vault and its folders is a note. Vaults are only ever read.
+
+use crate::{format_timestamp, Note};
+use chrono::{DateTime, FixedOffset, Utc};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use walkdir::{DirEntry, WalkDir};
+
+/// Reads the notes in `vault`, oldest file first. A note's timestamp is the
+/// modification time of its file, and unless the file starts with a heading
+/// its name is added as the note's title. YAML front matter is left out. Ids
+/// are positions and the HTML is left empty, as with `parse_note_blocks`.
+pub fn load(vault: &Path, timestamp_format: &str, timezone: Option<FixedOffset>) -> Vec<Note> {
+    let mut files = note_files(vault)
+        .into_iter()
+        .filter_map(|path| {
+            let modified = fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+            Some((modified, path))
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+
+    let mut notes = Vec::new();
+    for (modified, path) in files {
+        let Ok(content) = fs::read_to_string(&path) else {
+            continue;
+        };
+        let content = without_front_matter(&content).trim();
+        let title = path.file_stem().unwrap_or_default().to_string_lossy();
+        let content = if content.starts_with("# ") {
+            content.to_string()
+        } else {
+            format!("# {title}\n\n{content}").trim().to_string()
+        };
+
+        let timestamp =
+            format_timestamp(DateTime::<Utc>::from(modified), timezone, timestamp_format);
+        notes.push(Note::new(notes.len(), timestamp, content));
+    }
+    notes
+}
+
+/// Latest modification time and total size of the vault's notes, to notice
+/// changes made in Obsidian.
+pub fn version(vault: &Path) -> Option<(Option<SystemTime>, u64)> {
+    let mut latest = fs::metadata(vault).and_then(|m| m.modified()).ok()?;
+    let mut size = 0;
+    for path in note_files(vault) {
+        if let Ok(metadata) = fs::metadata(path) {
+            latest = latest.max(metadata.modified().unwrap_or(latest));
+            size += metadata.len();
+        }
+    }
+    Some((Some(latest), size))
+}
+
+// `.md` files, leaving out hidden folders like `.obsidian` (settings) and
+// `.trash` (deleted notes).
+fn note_files(vault: &Path) -> Vec<PathBuf> {
+    let hidden = |entry: &DirEntry| {
+        entry.depth() > 0 && entry.file_name().to_string_lossy().starts_with('.')
+    };
+    WalkDir::new(vault)
+        .follow_links(true)
+        .into_iter()
+        .filter_entry(|entry| !hidden(entry))
+        .flatten()
+        .filter(|entry| entry.file_type().is_file())
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "md"))
+        .map(DirEntry::into_path)
+        .collect()
+}
+
+// `content` without the `---` delimited properties Obsidian keeps at the top.
+fn without_front_matter(content: &str) -> &str {
+    let Some(rest) = content.strip_prefix("---\n") else {
+        return content;
+    };
+    match rest.find("\n---\n") {
+        Some(end) => &rest[end + "\n---\n".len()..],
+        None => rest.strip_suffix("\n---").map_or(content, |_| ""),
+    }
+}
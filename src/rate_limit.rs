@@ -0,0 +1,54 @@
+// Per-client limits on how often a route can be used, counted in fixed
+// one-minute windows.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+const WINDOW: Duration = Duration::from_secs(60);
+// Clients whose window is over are forgotten once there are this many.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    per_minute: usize,
+    clients: Arc<Mutex<HashMap<IpAddr, (usize, Instant)>>>,
+}
+
+impl RateLimiter {
+    /// A limiter allowing `per_minute` requests per client; 0 allows any number.
+    pub fn new(per_minute: usize) -> Self {
+        RateLimiter {
+            per_minute,
+            clients: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Counts `requests` requests from `client`, all or none of them. Returns
+    /// how long it has to wait if that would be too many.
+    pub fn check(&self, client: IpAddr, requests: usize) -> Result<(), Duration> {
+        if self.per_minute == 0 || requests == 0 {
+            return Ok(());
+        }
+
+        let mut clients = self.clients.lock().unwrap();
+        let now = Instant::now();
+        if clients.len() >= MAX_TRACKED_CLIENTS {
+            clients.retain(|_, (_, started)| now.duration_since(*started) < WINDOW);
+        }
+
+        let (count, started) = clients.entry(client).or_insert((0, now));
+        if now.duration_since(*started) >= WINDOW {
+            *count = 0;
+            *started = now;
+        }
+        if *count + requests > self.per_minute {
+            return Err(WINDOW - now.duration_since(*started));
+        }
+        *count += requests;
+        Ok(())
+    }
+}
@@ -0,0 +1,88 @@
+// Detection of file types from their content ("magic bytes"), for checking
+// uploads against `--allowed-types`.
+
+// Signatures at the start of a file; `None` matches any byte.
+const SIGNATURES: &[(&[Option<u8>], &str)] = &[
+    (&bytes(b"\x89PNG\r\n\x1a\n"), "image/png"),
+    (&bytes(b"\xff\xd8\xff"), "image/jpeg"),
+    (&bytes(b"GIF87a"), "image/gif"),
+    (&bytes(b"GIF89a"), "image/gif"),
+    (&riff(b"WEBP"), "image/webp"),
+    (&riff(b"WAVE"), "audio/wav"),
+    (&riff(b"AVI "), "video/x-msvideo"),
+    (&bytes(b"BM"), "image/bmp"),
+    (&bytes(b"II*\0"), "image/tiff"),
+    (&bytes(b"MM\0*"), "image/tiff"),
+    (&bytes(b"\0\0\x01\0"), "image/x-icon"),
+    (&bytes(b"%PDF-"), "application/pdf"),
+    (&bytes(b"PK\x03\x04"), "application/zip"),
+    (&bytes(b"\x1f\x8b"), "application/gzip"),
+    (&bytes(b"7z\xbc\xaf\x27\x1c"), "application/x-7z-compressed"),
+    (&bytes(b"Rar!\x1a\x07"), "application/vnd.rar"),
+    (&bytes(b"\x1a\x45\xdf\xa3"), "video/webm"),
+    (&bytes(b"OggS"), "audio/ogg"),
+    (&bytes(b"fLaC"), "audio/flac"),
+    (&bytes(b"ID3"), "audio/mpeg"),
+    (&bytes(b"\x7fELF"), "application/x-executable"),
+    (&bytes(b"MZ"), "application/x-msdownload"),
+];
+
+const fn bytes<const N: usize>(signature: &[u8; N]) -> [Option<u8>; N] {
+    let mut pattern = [None; N];
+    let mut i = 0;
+    while i < N {
+        pattern[i] = Some(signature[i]);
+        i += 1;
+    }
+    pattern
+}
+
+// RIFF containers have the file size in bytes 4..8 and the format after it.
+const fn riff(format: &[u8; 4]) -> [Option<u8>; 12] {
+    let mut pattern = [None; 12];
+    let riff = b"RIFF";
+    let mut i = 0;
+    while i < 4 {
+        pattern[i] = Some(riff[i]);
+        pattern[8 + i] = Some(format[i]);
+        i += 1;
+    }
+    pattern
+}
+
+/// The MIME type of `data` if it starts with a known signature.
+pub fn sniff(data: &[u8]) -> Option<&'static str> {
+    // ISO media (MP4, MOV, ...) has the `ftyp` box at offset 4
+    if data.len() >= 12 && &data[4..8] == b"ftyp" {
+        return Some(match &data[8..12] {
+            b"qt  " => "video/quicktime",
+            b"M4A " => "audio/mp4",
+            b"avif" => "image/avif",
+            b"heic" | b"heix" => "image/heic",
+            _ => "video/mp4",
+        });
+    }
+
+    SIGNATURES
+        .iter()
+        .find(|(signature, _)| {
+            data.len() >= signature.len()
+                && signature
+                    .iter()
+                    .zip(data)
+                    .all(|(expected, byte)| expected.is_none_or(|expected| expected == *byte))
+        })
+        .map(|(_, mime)| *mime)
+}
+
+/// The MIME type of `data`: a known signature, else `text/plain` for UTF-8
+/// text, else `application/octet-stream`.
+pub fn detect(data: &[u8]) -> &'static str {
+    sniff(data).unwrap_or_else(|| {
+        if !data.contains(&0) && std::str::from_utf8(data).is_ok() {
+            "text/plain"
+        } else {
+            "application/octet-stream"
+        }
+    })
+}
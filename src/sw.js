@@ -0,0 +1,23 @@
+// Keeps a copy of the page so textpod still opens when the server is unreachable.
+const CACHE = 'textpod-shell-v1';
+
+self.addEventListener('install', (event) => {
+    event.waitUntil(caches.open(CACHE).then((cache) => cache.add('/')));
+});
+
+self.addEventListener('fetch', (event) => {
+    if (event.request.mode !== 'navigate') {
+        return;
+    }
+
+    // network first, so the page is always fresh when the server is up
+    event.respondWith(
+        fetch(event.request)
+            .then((response) => {
+                const copy = response.clone();
+                caches.open(CACHE).then((cache) => cache.put('/', copy));
+                return response;
+            })
+            .catch(() => caches.match('/'))
+    );
+});